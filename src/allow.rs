@@ -0,0 +1,169 @@
+//!
+//! Checking `Origin` and `Host` headers, so web pages can't talk
+//! to us (directly, or through DNS rebinding) unless they're allowed to.
+//!
+
+use std::{net::IpAddr, sync::Arc};
+
+use warp::{http::StatusCode, path::FullPath, reject::Reject, Filter, Rejection, Reply};
+
+///
+/// The `Origin` of Chrome's bundled DevTools frontend.
+///
+const DEVTOOLS_ORIGIN: &str = "devtools://devtools";
+
+///
+/// Which `Origin`s and `Host`s may use the server,
+/// like Chrome's `--remote-allow-origins`.
+///
+/// Patterns can use `*` as a wildcard (e.g. `https://*.example.com`, `http://localhost:*`),
+/// and are matched ignoring case.
+///
+/// By default:
+/// * requests without an `Origin` (i.e. not from a web page) are allowed, as is
+///   Chrome's own frontend (`devtools://devtools`), and all others aren't.
+/// * `Host` may be `localhost`, or any IP address, which can't be rebound.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    origins: Vec<String>,
+    hosts: Vec<String>,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Allow web pages from origins matching `pattern` (`*` for any).
+    ///
+    pub fn origin(mut self, pattern: impl Into<String>) -> Self {
+        self.origins.push(pattern.into());
+        self
+    }
+
+    ///
+    /// Allow `Host`s (without the port) matching `pattern`.
+    ///
+    pub fn host(mut self, pattern: impl Into<String>) -> Self {
+        self.hosts.push(pattern.into());
+        self
+    }
+
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        match origin {
+            None => true,
+            // Like Chrome, which always lets its bundled frontend in.
+            Some(origin) if origin.eq_ignore_ascii_case(DEVTOOLS_ORIGIN) => true,
+            Some(origin) => self.origins.iter().any(|p| glob(p, origin)),
+        }
+    }
+
+    pub fn allows_host(&self, host: Option<&str>) -> bool {
+        let Some(host) = host else {
+            return true;
+        };
+
+        let name = strip_port(host);
+        let ip = name.trim_start_matches('[').trim_end_matches(']');
+
+        name.eq_ignore_ascii_case("localhost")
+            || ip.parse::<IpAddr>().is_ok()
+            || self.hosts.iter().any(|p| glob(p, name))
+    }
+}
+
+#[derive(Debug)]
+struct Forbidden;
+
+impl Reject for Forbidden {}
+
+///
+/// Rejects requests from disallowed origins/hosts
+/// (see [`forbidden`] to turn them into 403s).
+///
+pub(crate) fn check(allow: Arc<Allowlist>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::path::full())
+        .and_then(
+            move |origin: Option<String>, host: Option<String>, path: FullPath| {
+                let allow = allow.clone();
+                async move {
+                    let origin_ok = allow.allows_origin(origin.as_deref());
+                    let host_ok = allow.allows_host(host.as_deref());
+
+                    if origin_ok && host_ok {
+                        return Ok(());
+                    }
+
+                    tracing::warn!(
+                        path = path.as_str(),
+                        origin = origin.as_deref().unwrap_or_default(),
+                        host = host.as_deref().unwrap_or_default(),
+                        "Rejected request from disallowed {}",
+                        if origin_ok { "Host" } else { "Origin" }
+                    );
+
+                    Err(warp::reject::custom(Forbidden))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+pub(crate) async fn forbidden(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Forbidden>().is_some() {
+        Ok(warp::reply::with_status(
+            "Forbidden (see Allowlist)",
+            StatusCode::FORBIDDEN,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+///
+/// `host:port` -> `host`, minding IPv6 addresses.
+///
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, _)) if !name.contains(':') => name,
+        // No port, or a bare IPv6 address.
+        _ => host,
+    }
+}
+
+///
+/// Whether `value` matches `pattern`, with `*` matching anything (including nothing).
+///
+fn glob(pattern: &str, value: &str) -> bool {
+    let (p, v) = (pattern.as_bytes(), value.as_bytes());
+    let (mut pi, mut vi) = (0, 0);
+
+    // Where to go back to if a match after a `*` fails.
+    let mut star = None;
+
+    while vi < v.len() {
+        if pi < p.len() && p[pi] == b'*' {
+            star = Some((pi, vi));
+            pi += 1;
+        } else if pi < p.len() && p[pi].eq_ignore_ascii_case(&v[vi]) {
+            pi += 1;
+            vi += 1;
+        } else if let Some((sp, sv)) = star {
+            pi = sp + 1;
+            vi = sv + 1;
+            star = Some((sp, sv + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == b'*')
+}
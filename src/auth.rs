@@ -0,0 +1,219 @@
+//!
+//! Optional token authentication, for sessions and `/json`.
+//!
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use warp::{Filter, Rejection};
+
+use crate::{meta::Target, roles::Role};
+
+///
+/// Tokens needed to open sessions: a shared secret (good for any target),
+/// and/or tokens for particular targets.
+///
+/// Clients pass them as `?token=...` on the `webSocketDebuggerUrl`,
+/// or as an `Authorization: Bearer ...` header.
+///
+/// A target is open to anyone if it has no token of its own,
+/// and there's no shared or role token.
+///
+/// Tokens can also come with a [`Role`], restricting what their sessions can call.
+///
+/// Cloneable, and shared with the [`ServerHandle`](crate::ServerHandle),
+/// so tokens can be rotated while the server is running
+/// (already open sessions are left alone).
+///
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    inner: Arc<RwLock<Tokens>>,
+}
+
+#[derive(Debug, Default)]
+struct Tokens {
+    shared: Option<String>,
+
+    ///
+    /// Target id -> token.
+    ///
+    targets: HashMap<String, String>,
+
+    ///
+    /// Token -> role, for tokens good for any target, but only some methods.
+    ///
+    roles: HashMap<String, Role>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared(self, token: impl Into<String>) -> Self {
+        self.set_shared(Some(token.into()));
+        self
+    }
+
+    pub fn target(self, id: impl Into<String>, token: impl Into<String>) -> Self {
+        self.set_target(id, Some(token.into()));
+        self
+    }
+
+    ///
+    /// A token good for any target, whose sessions can only call what `role` allows.
+    ///
+    pub fn role(self, token: impl Into<String>, role: Role) -> Self {
+        self.set_role(token, Some(role));
+        self
+    }
+
+    ///
+    /// Replace (or remove, with `None`) the shared secret.
+    ///
+    pub fn set_shared(&self, token: Option<String>) {
+        self.inner.write().unwrap().shared = token;
+    }
+
+    ///
+    /// Replace (or remove, with `None`) a target's token.
+    ///
+    pub fn set_target(&self, id: impl Into<String>, token: Option<String>) {
+        let mut tokens = self.inner.write().unwrap();
+        match token {
+            Some(token) => tokens.targets.insert(id.into(), token),
+            None => tokens.targets.remove(&id.into()),
+        };
+    }
+
+    ///
+    /// Add, change or (with `None`) remove a token with a role.
+    ///
+    pub fn set_role(&self, token: impl Into<String>, role: Option<Role>) {
+        let mut tokens = self.inner.write().unwrap();
+        match role {
+            Some(role) => tokens.roles.insert(token.into(), role),
+            None => tokens.roles.remove(&token.into()),
+        };
+    }
+
+    ///
+    /// The role `token`'s sessions have, if they're restricted.
+    ///
+    pub fn role_of(&self, token: Option<&str>) -> Option<Role> {
+        let token = token?;
+        let tokens = self.inner.read().unwrap();
+
+        tokens
+            .roles
+            .iter()
+            .find(|(t, _)| same(t, token))
+            .map(|(_, role)| role.clone())
+    }
+
+    ///
+    /// Whether `token` (if any) lets its bearer open a session to `target`.
+    ///
+    pub fn allows(&self, target: &str, token: Option<&str>) -> bool {
+        let tokens = self.inner.read().unwrap();
+        let own = tokens.targets.get(target);
+
+        if own.is_none() && tokens.shared.is_none() && tokens.roles.is_empty() {
+            return true;
+        }
+
+        let Some(token) = token else {
+            return false;
+        };
+
+        own.is_some_and(|t| same(t, token))
+            || tokens.shared.as_ref().is_some_and(|t| same(t, token))
+            || tokens.roles.keys().any(|t| same(t, token))
+    }
+
+    ///
+    /// `targets`, with `token` added to the URLs of
+    /// those it's good for (so only for authenticated requesters).
+    ///
+    pub(crate) fn embed(&self, targets: &[Target], token: Option<&str>) -> Vec<Target> {
+        let Some(token) = token else {
+            return targets.to_vec();
+        };
+
+        let query = format!("token={}", encode(token));
+
+        targets
+            .iter()
+            .cloned()
+            .map(|mut target| {
+                if !self.allows(&target.id, Some(token)) {
+                    return target;
+                }
+
+                if !target.web_socket_debugger_url.is_empty() {
+                    let sep = if target.web_socket_debugger_url.contains('?') {
+                        '&'
+                    } else {
+                        '?'
+                    };
+                    target.web_socket_debugger_url =
+                        format!("{}{sep}{query}", target.web_socket_debugger_url);
+                }
+
+                // The frontend's `ws=` parameter is itself a (query-less) URL.
+                if let Some(ref mut url) = target.devtools_frontend_url {
+                    url.push_str(&encode(&format!("?{query}")));
+                }
+
+                target
+            })
+            .collect()
+    }
+}
+
+///
+/// The requester's token, from `?token=...` or `Authorization: Bearer ...`.
+///
+pub(crate) fn token() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .or(warp::any().map(HashMap::new))
+        .unify()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(
+            |mut query: HashMap<String, String>, header: Option<String>| {
+                query.remove("token").or_else(|| {
+                    header
+                        .as_deref()
+                        .and_then(|h| h.strip_prefix("Bearer "))
+                        .map(|t| t.trim().to_string())
+                })
+            },
+        )
+}
+
+///
+/// Compare tokens without giving away (through timing) how much of one matched.
+///
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+///
+/// Percent-encode everything but unreserved characters.
+///
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
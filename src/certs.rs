@@ -0,0 +1,107 @@
+//!
+//! Generating self-signed certificates for [`TLS`](crate::TLS),
+//! so HTTPS works without any external tooling.
+//!
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use ring::digest;
+
+use crate::tls::{Pem, TLS};
+
+///
+/// An ephemeral, self-signed certificate for `localhost`, `127.0.0.1`, `::1`
+/// and any other hosts added.
+///
+#[derive(Debug, Clone, Default)]
+pub struct SelfSigned {
+    hosts: Vec<String>,
+
+    ///
+    /// Where to also write `cert.pem` and `key.pem`, if anywhere.
+    ///
+    persist: Option<PathBuf>,
+}
+
+impl SelfSigned {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Also valid for `host` (a DNS name or IP address).
+    ///
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.push(host.into());
+        self
+    }
+
+    ///
+    /// Write the certificate and key to `dir` (as `cert.pem` and `key.pem`),
+    /// e.g. for adding to a browser's trust store.
+    ///
+    pub fn persist(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.persist = Some(dir.into());
+        self
+    }
+
+    ///
+    /// Generate the certificate, for serving HTTPS on `port`.
+    ///
+    pub fn generate(self, port: u16) -> anyhow::Result<TLS> {
+        let mut names = vec![
+            "localhost".to_string(),
+            "127.0.0.1".to_string(),
+            "::1".to_string(),
+        ];
+        names.extend(self.hosts);
+
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)
+            .context("Could not generate self-signed certificate")?;
+
+        let (cert_pem, key_pem) = (cert.pem(), key_pair.serialize_pem());
+        let fingerprint = fingerprint(cert.der());
+
+        if let Some(ref dir) = self.persist {
+            write(dir, &cert_pem, &key_pem)
+                .with_context(|| format!("Could not write certificate to {}", dir.display()))?;
+            tracing::info!(dir = %dir.display(), "Wrote self-signed certificate");
+        }
+
+        tracing::info!(%fingerprint, "Generated self-signed certificate (SHA-256)");
+
+        let mut tls = TLS::new(port, Pem::bytes(cert_pem), Pem::bytes(key_pem));
+        tls.fingerprint = Some(fingerprint);
+        Ok(tls)
+    }
+}
+
+///
+/// SHA-256 of a DER certificate, as `AB:CD:...`
+///
+pub fn fingerprint(der: &[u8]) -> String {
+    digest::digest(&digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn write(dir: &Path, cert: &str, key: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join("cert.pem"), cert)?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // Nobody else needs to read the key.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    io::Write::write_all(&mut options.open(dir.join("key.pem"))?, key.as_bytes())
+}
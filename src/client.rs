@@ -0,0 +1,218 @@
+//!
+//! A (typed) client for any DevTools endpoint,
+//! including our own [`DevToolsServer`](crate::DevToolsServer).
+//!
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrome_devtools_api::{Command, Event};
+use futures_util::{stream::BoxStream, SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    jsonrpc::{Request, Response, RpcError},
+    transport::{Frame, Transport},
+};
+
+///
+/// Requests waiting on replies, or `None` once the connection's closed.
+///
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Response>>>>>;
+
+///
+/// Connection to a single DevTools target.
+///
+/// Replies are matched up to their requests by `id`,
+/// and everything else is broadcast as an event.
+///
+/// Once the connection closes, requests fail and event streams end.
+///
+pub struct DevToolsClient {
+    requests: mpsc::Sender<Request>,
+    pending: Pending,
+
+    ///
+    /// Only for subscribing, the sender goes when the connection does.
+    ///
+    events: broadcast::Receiver<Response>,
+    next_id: AtomicU64,
+}
+
+impl DevToolsClient {
+    ///
+    /// Connect to a `webSocketDebuggerUrl`.
+    ///
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let (websocket, _) = tokio_tungstenite::connect_async(url).await?;
+        Ok(Self::over(websocket))
+    }
+
+    ///
+    /// Talk to a target over any [`Transport`],
+    /// e.g. a [`Pipe`](crate::Pipe) to a child process.
+    ///
+    pub fn over(transport: impl Transport) -> Self {
+        let (mut outbound, mut inbound) = transport.split();
+        let (requests, mut requests_rx) = mpsc::channel::<Request>(16);
+        let (events, events_rx) = broadcast::channel(256);
+        let pending = Pending::new(Mutex::new(Some(HashMap::new())));
+
+        {
+            let pending = pending.clone();
+
+            tokio::spawn(async move {
+                while let Some(req) = requests_rx.recv().await {
+                    let req = serde_json::to_string(&req).expect("Could not Serialize Request");
+                    if outbound.send(Frame::Text(req)).await.is_err() {
+                        // Nothing more's getting through, so no replies are coming.
+                        pending.lock().unwrap().take();
+                        break;
+                    }
+                }
+
+                let _ = outbound.send(Frame::Close(None)).await;
+            });
+        }
+
+        {
+            let pending = pending.clone();
+
+            tokio::spawn(async move {
+                while let Some(Ok(frame)) = inbound.next().await {
+                    let res = match frame {
+                        Frame::Text(text) => match serde_json::from_str::<Response>(&text) {
+                            Ok(res) => res,
+                            Err(_) => continue,
+                        },
+                        Frame::Close(_) => break,
+                        _ => continue,
+                    };
+
+                    match res.id.as_ref().and_then(serde_json::Value::as_u64) {
+                        Some(id) => {
+                            let tx = pending.lock().unwrap().as_mut().and_then(|p| p.remove(&id));
+                            if let Some(tx) = tx {
+                                let _ = tx.send(res);
+                            }
+                        }
+                        None if res.method.is_some() => {
+                            // No one listening is fine.
+                            let _ = events.send(res);
+                        }
+                        None => {}
+                    }
+                }
+
+                // Wake anyone still waiting (and fail anything sent later),
+                // there's no reply coming. Dropping `events` ends event streams.
+                pending.lock().unwrap().take();
+            });
+        }
+
+        Self {
+            requests,
+            pending,
+            events: events_rx,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    ///
+    /// Send a typed command, and wait for its (typed) reply.
+    ///
+    pub async fn send<C>(&self, _: C, params: C::Parameters) -> Result<C::Returns, RpcError>
+    where
+        C: Command,
+        C::Parameters: Serialize,
+        C::Returns: DeserializeOwned,
+    {
+        let params = serde_json::to_value(params).map_err(RpcError::internal_error)?;
+        let result = self.send_raw(&C::id().to_string(), params).await?;
+
+        serde_json::from_value(result).map_err(RpcError::internal_error)
+    }
+
+    ///
+    /// Send any method, and wait for its raw result.
+    ///
+    pub async fn send_raw(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, RpcError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(RpcError::internal_error("Connection closed")),
+        };
+
+        let req = Request {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(id.into()),
+        };
+
+        if self.requests.send(req).await.is_err() {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(RpcError::internal_error("Connection closed"));
+        }
+
+        let res = rx
+            .await
+            .map_err(|_| RpcError::internal_error("Connection closed"))?;
+
+        match res.error {
+            Some(e) => Err(e),
+            None => Ok(res.result.unwrap_or_default()),
+        }
+    }
+
+    ///
+    /// Every event from here on.
+    ///
+    pub fn raw_events(&self) -> BoxStream<'static, Response> {
+        futures::stream::unfold(self.events.resubscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    // Slow consumers just miss out.
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    ///
+    /// Every `E` from here on.
+    ///
+    pub fn events<E>(&self) -> BoxStream<'static, E>
+    where
+        E: Event + DeserializeOwned + Send + 'static,
+    {
+        let id = E::__id().to_string();
+
+        self.raw_events()
+            .filter_map(move |event| {
+                let event = (event.method.as_deref() == Some(id.as_str()))
+                    .then_some(event.params)
+                    .flatten()
+                    .and_then(|params| serde_json::from_value(params).ok());
+
+                futures::future::ready(event)
+            })
+            .boxed()
+    }
+}
@@ -0,0 +1,106 @@
+//!
+//! Finding targets through a DevTools server's `/json` endpoints,
+//! the same way `chrome://inspect` does.
+//!
+//! Only plain `http://` endpoints are supported.
+//!
+
+use anyhow::{anyhow, bail};
+use hyper::{body, client::HttpConnector, Client, StatusCode, Uri};
+use serde::de::DeserializeOwned;
+
+use crate::{client::DevToolsClient, meta::BrowserVersion, meta::Target};
+
+///
+/// Which target to pick out of `/json/list`.
+///
+#[derive(Debug, Clone)]
+pub enum TargetQuery {
+    ///
+    /// Exact id.
+    ///
+    Id(String),
+
+    ///
+    /// Title containing this.
+    ///
+    Title(String),
+
+    ///
+    /// Type (`page`, `node`, `other`, ...), ignoring case.
+    ///
+    Type(String),
+}
+
+impl TargetQuery {
+    pub fn matches(&self, target: &Target) -> bool {
+        match self {
+            TargetQuery::Id(id) => target.id() == id,
+            TargetQuery::Title(title) => target.title().contains(title.as_str()),
+            TargetQuery::Type(ty) => target.target_type().eq_ignore_ascii_case(ty),
+        }
+    }
+}
+
+pub struct Discovery {
+    base: String,
+    client: Client<HttpConnector>,
+}
+
+impl Discovery {
+    ///
+    /// `base` being the server's address, e.g. `http://localhost:9222`.
+    ///
+    pub fn new(base: impl AsRef<str>) -> Self {
+        Self {
+            base: base.as_ref().trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    pub async fn version(&self) -> anyhow::Result<BrowserVersion> {
+        self.get("/json/version").await
+    }
+
+    pub async fn targets(&self) -> anyhow::Result<Vec<Target>> {
+        self.get("/json/list").await
+    }
+
+    ///
+    /// First target matching `query`, if any.
+    ///
+    pub async fn find(&self, query: &TargetQuery) -> anyhow::Result<Option<Target>> {
+        Ok(self.targets().await?.into_iter().find(|t| query.matches(t)))
+    }
+
+    ///
+    /// Open a session to the first target matching `query`.
+    ///
+    pub async fn connect(&self, query: &TargetQuery) -> anyhow::Result<DevToolsClient> {
+        let target = self
+            .find(query)
+            .await?
+            .ok_or_else(|| anyhow!("No target matching {query:?}"))?;
+
+        if target.web_socket_debugger_url().is_empty() {
+            bail!(
+                "Target {} has no webSocketDebuggerUrl (is something else attached?)",
+                target.id()
+            );
+        }
+
+        DevToolsClient::connect(target.web_socket_debugger_url()).await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        let uri: Uri = format!("{}{path}", self.base).parse()?;
+        let res = self.client.get(uri.clone()).await?;
+
+        if res.status() != StatusCode::OK {
+            bail!("GET {uri} returned {}", res.status());
+        }
+
+        let body = body::to_bytes(res.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
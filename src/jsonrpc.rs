@@ -23,6 +23,25 @@ impl RpcError {
             data: None,
         }
     }
+
+    ///
+    /// Chrome's catch-all for commands it won't (or can't) carry out.
+    ///
+    pub fn server_error(message: impl ToString) -> Self {
+        Self {
+            code: -32000,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn internal_error(data: impl ToString) -> Self {
+        Self {
+            code: -32603,
+            message: "Internal error".to_string(),
+            data: Some(data.to_string().into()),
+        }
+    }
 }
 
 fn empty_obj() -> serde_json::Value {
@@ -215,6 +234,22 @@ pub fn invalid_request(
     }
 }
 
+///
+/// A request over one of the session's [`Limits`](crate::Limits).
+///
+pub fn limit_exceeded(
+    id: impl Into<Option<serde_json::Value>>,
+    message: impl ToString,
+) -> Response {
+    Response {
+        id: id.into(),
+        method: None,
+        result: None,
+        error: Some(RpcError::server_error(message)),
+        ..Default::default()
+    }
+}
+
 pub fn method_not_found(id: Option<serde_json::Value>) -> serde_json::Value {
     json!({
         "jsonrpc": "2.0",
@@ -1,14 +1,47 @@
-#![feature(allocator_api, negative_impls)]
+#![feature(negative_impls)]
 
+pub mod allow;
+pub mod auth;
+pub mod certs;
+pub mod client;
+pub mod discovery;
 pub mod jsonrpc;
 pub mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod record;
+pub mod redact;
+pub mod registry;
+pub mod replay;
+pub mod roles;
 pub mod server;
+pub mod testing;
+pub mod tls;
 pub mod traffic;
+pub mod transport;
 pub mod util;
 
+pub use allow::Allowlist;
+pub use auth::Auth;
+pub use certs::SelfSigned;
+pub use client::DevToolsClient;
+pub use discovery::{Discovery, TargetQuery};
 pub use meta::{BrowserVersion, Target};
-pub use server::{DevToolsServer, TLS};
-pub use util::{Handler, HandlerBuilder};
+#[cfg(feature = "metrics")]
+pub use metrics::Metrics;
+pub use record::Recorder;
+pub use redact::Redactor;
+pub use registry::Registry;
+pub use replay::Replay;
+pub use roles::Role;
+pub use server::{DevToolsServer, ServerHandle};
+pub use testing::TestSession;
+pub use tls::{Pem, TLS};
+pub use traffic::{
+    AttachPolicy, Attached, Backpressure, Channels, Keepalive, Limits, SessionInfo, SessionOptions,
+};
+pub use transport::{Frame, Pipe, Transport};
+pub use util::{Fallback, Handler, HandlerBuilder};
 
 #[cfg(test)]
 mod tests {
@@ -22,6 +55,95 @@ mod tests {
 
     use crate::{util::Forwarder, BrowserVersion, DevToolsServer, HandlerBuilder, Target, TLS, jsonrpc::Request};
 
+    ///
+    /// The client's end of a session served over an in-memory pipe.
+    ///
+    struct PipeClient {
+        read: tokio::io::BufReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>,
+        write: tokio::io::WriteHalf<tokio::io::DuplexStream>,
+    }
+
+    impl PipeClient {
+        fn new(builder: HandlerBuilder, options: crate::SessionOptions, target: &str) -> Self {
+            Self::with_buffer(builder, options, target, 64 << 10)
+        }
+
+        ///
+        /// With only `buffer` bytes between us and the server, to play a slow client.
+        ///
+        fn with_buffer(
+            builder: HandlerBuilder,
+            options: crate::SessionOptions,
+            target: &str,
+            buffer: usize,
+        ) -> Self {
+            Self::over(serve_pipe(builder, options, target, buffer))
+        }
+
+        fn over(stream: tokio::io::DuplexStream) -> Self {
+            let (read, write) = tokio::io::split(stream);
+            Self {
+                read: tokio::io::BufReader::new(read),
+                write,
+            }
+        }
+
+        async fn send(&mut self, msg: impl AsRef<[u8]>) -> anyhow::Result<()> {
+            use tokio::io::AsyncWriteExt;
+
+            self.write.write_all(msg.as_ref()).await?;
+            self.write.write_all(b"\0").await?;
+            Ok(())
+        }
+
+        ///
+        /// The next message, or `None` once the server's closed the pipe.
+        ///
+        async fn next(&mut self) -> anyhow::Result<Option<crate::jsonrpc::Response>> {
+            use tokio::io::AsyncBufReadExt;
+
+            let mut msg = vec![];
+            if self.read.read_until(b'\0', &mut msg).await? == 0 {
+                return Ok(None);
+            }
+            msg.pop();
+            Ok(Some(serde_json::from_slice(&msg)?))
+        }
+    }
+
+    ///
+    /// Serve a session over an in-memory pipe (of `buffer` bytes),
+    /// handing back the client's end.
+    ///
+    fn serve_pipe(
+        builder: HandlerBuilder,
+        options: crate::SessionOptions,
+        target: &str,
+        buffer: usize,
+    ) -> tokio::io::DuplexStream {
+        let (server, client) = tokio::io::duplex(buffer);
+        let (read, write) = tokio::io::split(server);
+
+        tokio::spawn(DevToolsServer::handle_client(
+            crate::Pipe::new(read, write),
+            builder,
+            options,
+            crate::SessionInfo::new(target),
+        ));
+
+        client
+    }
+
+    ///
+    /// Session options without pings or idle timeouts.
+    ///
+    fn quiet() -> crate::SessionOptions {
+        crate::SessionOptions {
+            keepalive: None,
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_server() -> anyhow::Result<()> {
         let server = DevToolsServer::new(
@@ -78,13 +200,1407 @@ mod tests {
 
                 builder
             }),
-            TLS {
-                port: 9003,
-                certificate: "keys/cert.pem",
-                private_key: "keys/key.pem",
-            },
+            TLS::self_signed(9003)?,
         );
 
         server.run().await
     }
+
+    #[tokio::test]
+    async fn test_session() -> anyhow::Result<()> {
+        use crate::{jsonrpc::Response, traffic::dispatch_event, TestSession};
+
+        let mut builder = HandlerBuilder::default();
+        builder.add_listener(protocol::dom::GetDocument, |_, events_tx| {
+            dispatch_event(
+                &events_tx,
+                Response {
+                    method: Some("DOM.documentUpdated".to_string()),
+                    result: None,
+                    params: Some(serde_json::json!({})),
+                    id: None,
+                    ..Default::default()
+                },
+            );
+
+            Ok(GetDocumentReturns {
+                root: Node {
+                    node_id: 1,
+                    node_name: "DOCUMENT".to_string(),
+                    ..Default::default()
+                },
+            })
+        });
+
+        let mut session = TestSession::new(builder);
+        let document = session
+            .send(protocol::dom::GetDocument, Default::default())
+            .await
+            .map_err(|e| anyhow::anyhow!(e.message))?;
+        assert_eq!(document.root.node_name, "DOCUMENT");
+
+        let event = session.next_event().await.expect("No event emitted");
+        assert_eq!(event.method.as_deref(), Some("DOM.documentUpdated"));
+
+        let err = session
+            .send_raw("DOM.doesNotExist", serde_json::json!({}))
+            .await;
+        assert_eq!(err.error.map(|e| e.code), Some(-32600));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fallback() -> anyhow::Result<()> {
+        use serde_json::json;
+
+        use crate::{jsonrpc::Response, Fallback, TestSession};
+
+        let session = |fallback| {
+            let mut builder = HandlerBuilder::default();
+            builder
+                .add_listener(protocol::dom::GetDocument, |_, _| {
+                    Ok(GetDocumentReturns {
+                        root: Node {
+                            node_name: "DOCUMENT".to_string(),
+                            ..Default::default()
+                        },
+                    })
+                })
+                .fallback(fallback);
+            TestSession::new(builder)
+        };
+
+        // By domain (with or without the dot), and by method.
+        let actions = ["Page", "Overlay.", "Network.enable"];
+        let mut acks = session(Fallback::acknowledge(actions));
+        let res = acks.send_raw("Network.enable", json!({})).await;
+        assert_eq!(res.result, Some(json!({})));
+        for method in ["Page.setAdBlockingEnabled", "Overlay.hide"] {
+            let res = acks.send_raw(method, json!({})).await;
+            assert!(res.error.is_none(), "{method} wasn't acknowledged");
+            assert_eq!(res.result, Some(json!({})));
+        }
+        for method in ["Network.disable", "PageExtra.thing"] {
+            let code = acks.send_raw(method, json!({})).await.error.map(|e| e.code);
+            assert_eq!(code, Some(-32600), "{method} was acknowledged");
+        }
+
+        let mut everything = session(Fallback::acknowledge(["*"]));
+        let res = everything.send_raw("Anything.atAll", json!({})).await;
+        assert_eq!(res.result, Some(json!({})));
+
+        // Registered listeners still come first.
+        let document = everything
+            .send(protocol::dom::GetDocument, Default::default())
+            .await
+            .map_err(|e| anyhow::anyhow!(e.message))?;
+        assert_eq!(document.root.node_name, "DOCUMENT");
+
+        let mut caught = session(Fallback::listener(|req, _| {
+            Response::reply(&req, json!({ "caught": req.method }))
+        }));
+        let res = caught.send_raw("Overlay.hide", json!({})).await;
+        assert_eq!(res.result.unwrap()["caught"], "Overlay.hide");
+
+        let document = caught
+            .send(protocol::dom::GetDocument, Default::default())
+            .await
+            .map_err(|e| anyhow::anyhow!(e.message))?;
+        assert_eq!(document.root.node_name, "DOCUMENT");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipe() -> anyhow::Result<()> {
+        let mut client = PipeClient::new(HandlerBuilder::default(), quiet(), "TEST-1");
+        client
+            .send(r#"{"id":1,"method":"Schema.getDomains"}"#)
+            .await?;
+
+        let res = client.next().await?.unwrap();
+        assert_eq!(res.id, Some(1.into()));
+        assert_eq!(res.result.unwrap()["domains"][0]["name"], "Schema");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limits() -> anyhow::Result<()> {
+        use crate::{Limits, SessionOptions};
+
+        let options = SessionOptions {
+            limits: Limits {
+                max_message: 64,
+                max_depth: 2,
+                requests_per_second: Some(1),
+                sessions_per_target: Some(1),
+            },
+            ..quiet()
+        };
+        let session = || PipeClient::new(HandlerBuilder::default(), options.clone(), "TEST-1");
+
+        let mut client = session();
+        // Far bigger than we'd read, so the rest has to be skipped to find the next one.
+        let method = "A".repeat(1 << 20);
+        client
+            .send(format!(r#"{{"id":1,"method":"{method}"}}"#))
+            .await?;
+        client
+            .send(r#"{"id":2,"method":"A.b","params":{"a":{}}}"#)
+            .await?;
+        for id in [3, 4] {
+            client
+                .send(format!(r#"{{"id":{id},"method":"Schema.getDomains"}}"#))
+                .await?;
+        }
+
+        let mut replies = vec![];
+        for _ in 0..4 {
+            replies.push(client.next().await?.unwrap());
+        }
+
+        let code = |id: Option<serde_json::Value>| {
+            replies
+                .iter()
+                .filter(|r| r.id == id)
+                .map(|r| r.error.as_ref().map(|e| e.code))
+                .collect::<Vec<_>>()
+        };
+        // Too big, and too deep (neither parsed, so no id).
+        assert_eq!(code(None), [Some(-32000), Some(-32000)]);
+        assert_eq!(code(Some(3.into())), [None]);
+        // Too soon after the last one.
+        assert_eq!(code(Some(4.into())), [Some(-32000)]);
+        // Only errors, so not to be taken for events (or successes).
+        let mut errors = replies.iter().filter(|r| r.error.is_some());
+        assert!(errors.all(|r| r.method.is_none() && r.result.is_none()));
+
+        // The first session's still attached, so this one's closed straight away.
+        assert!(session().next().await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attach_policy() -> anyhow::Result<()> {
+        use crate::AttachPolicy;
+
+        let options = quiet();
+        options
+            .attached
+            .set_policy("TEST-1", AttachPolicy::ReplaceOld);
+        options
+            .attached
+            .set_policy("TEST-2", AttachPolicy::RejectNew);
+
+        let session = |target| PipeClient::new(HandlerBuilder::default(), options.clone(), target);
+
+        // Make sure the first session's attached before the next one comes along.
+        let ping = r#"{"id":1,"method":"Schema.getDomains"}"#;
+
+        let mut old = session("TEST-1");
+        old.send(ping).await?;
+        old.next().await?;
+
+        let mut new = session("TEST-1");
+        new.send(ping).await?;
+        assert!(new.next().await?.unwrap().error.is_none());
+
+        let detached = old.next().await?.unwrap();
+        assert_eq!(detached.method.as_deref(), Some("Inspector.detached"));
+        assert_eq!(detached.params.unwrap()["reason"], "replaced_with_devtools");
+
+        assert!(old.next().await?.is_none());
+        assert_eq!(options.attached.count("TEST-1"), 1);
+
+        let mut targets = [Target::default()];
+        options.attached.mark(&mut targets);
+        assert!(targets[0].attached());
+
+        let mut first = session("TEST-2");
+        first.send(ping).await?;
+        first.next().await?;
+
+        assert!(session("TEST-2").next().await?.is_none());
+        assert_eq!(options.attached.count("TEST-2"), 1);
+
+        // Replaced even while waiting on a forwarder that never answers.
+        options
+            .attached
+            .set_policy("TEST-3", AttachPolicy::ReplaceOld);
+        let registry = crate::Registry::new();
+        let (f_in, mut f_out) = Forwarder::new(["Echo."]).split();
+        registry.forward(f_in);
+        let forwarded = |target| {
+            let mut builder = HandlerBuilder::default();
+            builder.registry(&registry);
+            PipeClient::new(builder, options.clone(), target)
+        };
+
+        let mut old = forwarded("TEST-3");
+        old.send(r#"{"id":1,"method":"Echo.echo"}"#).await?;
+        let _in_flight = f_out.incoming().recv().await.unwrap();
+        let mut new = forwarded("TEST-3");
+        new.send(ping).await?;
+        new.next().await?;
+
+        let detached = old.next().await?.unwrap();
+        assert_eq!(detached.method.as_deref(), Some("Inspector.detached"));
+        assert!(old.next().await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client() -> anyhow::Result<()> {
+        use futures_util::StreamExt;
+
+        use crate::{jsonrpc::Response, traffic::dispatch_event, DevToolsClient, Pipe};
+
+        let mut builder = HandlerBuilder::default();
+        builder.add_listener(protocol::dom::GetDocument, |_, events_tx| {
+            dispatch_event(
+                &events_tx,
+                Response {
+                    method: Some("DOM.documentUpdated".to_string()),
+                    result: None,
+                    params: Some(serde_json::json!({})),
+                    id: None,
+                    ..Default::default()
+                },
+            );
+
+            Ok(GetDocumentReturns::default())
+        });
+
+        let (read, write) = tokio::io::split(serve_pipe(builder, quiet(), "TEST-1", 64 << 10));
+        let client = DevToolsClient::over(Pipe::new(read, write));
+        let mut events = client.raw_events();
+
+        client
+            .send(protocol::dom::GetDocument, Default::default())
+            .await
+            .map_err(|e| anyhow::anyhow!(e.message))?;
+
+        let event = events.next().await.expect("No event emitted");
+        assert_eq!(event.method.as_deref(), Some("DOM.documentUpdated"));
+
+        let err = client
+            .send_raw("DOM.doesNotExist", serde_json::json!({}))
+            .await
+            .expect_err("Unknown method should error");
+        assert_eq!(err.code, -32600);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use futures_util::StreamExt;
+        use tokio::io::AsyncBufReadExt;
+
+        use crate::{DevToolsClient, Pipe};
+
+        let (ours, theirs) = tokio::io::duplex(64 << 10);
+        let (read, write) = tokio::io::split(ours);
+        let client = DevToolsClient::over(Pipe::new(read, write));
+        let mut events = client.raw_events();
+
+        // A server that reads one request, then goes away without answering.
+        let server = tokio::spawn(async move {
+            let mut theirs = tokio::io::BufReader::new(theirs);
+            let mut req = vec![];
+            theirs.read_until(b'\0', &mut req).await.unwrap();
+        });
+
+        let in_flight = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.send_raw("DOM.getDocument", serde_json::json!({})),
+        );
+        assert!(in_flight.await?.is_err());
+        server.await?;
+
+        let later = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.send_raw("DOM.getDocument", serde_json::json!({})),
+        );
+        assert!(later.await?.is_err());
+
+        let ended = tokio::time::timeout(Duration::from_secs(5), events.next()).await?;
+        assert!(ended.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use futures_util::{SinkExt, StreamExt};
+        use tokio::{io::DuplexStream, task::JoinHandle};
+        use tokio_tungstenite::{
+            tungstenite::{protocol::Role, Message},
+            WebSocketStream,
+        };
+
+        use crate::{Backpressure, Channels, Keepalive, SessionInfo, SessionOptions};
+
+        async fn session(
+            builder: HandlerBuilder,
+            channels: Channels,
+        ) -> (WebSocketStream<DuplexStream>, JoinHandle<()>) {
+            let options = SessionOptions {
+                keepalive: Some(Keepalive {
+                    interval: Duration::from_secs(10),
+                    idle_timeout: Duration::from_secs(30),
+                }),
+                channels,
+                ..Default::default()
+            };
+            let (server, client) = tokio::io::duplex(64 << 10);
+            let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+            let handle = tokio::spawn(DevToolsServer::handle_client(
+                server,
+                builder,
+                options,
+                SessionInfo::new("TEST-1"),
+            ));
+
+            let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            (client, handle)
+        }
+
+        // Answering pings (tungstenite pongs as we read) keeps the session going.
+        let (mut ws, _) = session(HandlerBuilder::default(), Channels::default()).await;
+        for _ in 0..6 {
+            assert!(matches!(ws.next().await.unwrap()?, Message::Ping(_)));
+        }
+        let req = r#"{"id":1,"method":"DOM.getDocument","params":{}}"#;
+        ws.send(Message::Text(req.into())).await?;
+        let reply = loop {
+            match ws.next().await.unwrap()? {
+                Message::Ping(_) => continue,
+                msg => break msg,
+            }
+        };
+        assert!(matches!(reply, Message::Text(text) if text.contains(r#""id":1"#)));
+
+        // A client that goes quiet is closed after the idle timeout.
+        let (mut ws, handle) = session(HandlerBuilder::default(), Channels::default()).await;
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        tokio::time::timeout(Duration::from_secs(1), handle).await??;
+        // Nothing but pings (and the close) on the way out.
+        while let Some(Ok(msg)) = ws.next().await {
+            assert!(matches!(msg, Message::Ping(_) | Message::Close(_)));
+        }
+
+        // As is one that sends a close frame.
+        let (mut ws, handle) = session(HandlerBuilder::default(), Channels::default()).await;
+        ws.close(None).await?;
+        tokio::time::timeout(Duration::from_secs(1), handle).await??;
+
+        // Pipes can't answer pings, so a quiet parent is left alone.
+        let mut parent = PipeClient::new(HandlerBuilder::default(), Default::default(), "TEST-1");
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        parent
+            .send(r#"{"id":1,"method":"Schema.getDomains"}"#)
+            .await?;
+        assert!(parent.next().await?.is_some());
+
+        // One that only listens still gets pinged, however much there is to send.
+        let events = 100_000;
+        let mut builder = HandlerBuilder::default();
+        builder.on_start(move |tx| {
+            tokio::spawn(async move {
+                let tick = crate::jsonrpc::Response {
+                    method: Some("Test.tick".to_string()),
+                    result: None,
+                    id: None,
+                    ..Default::default()
+                };
+                for _ in 0..events {
+                    tx.send(tick.clone()).await.unwrap();
+                }
+            });
+        });
+        let channels = Channels {
+            events,
+            backpressure: Backpressure::Block,
+            ..Default::default()
+        };
+        let (mut ws, handle) = session(builder, channels).await;
+        let start = tokio::time::Instant::now();
+        let mut pings = 0;
+        while start.elapsed() < Duration::from_secs(60) {
+            // Far slower than the events pile up, so the queue never runs dry.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            match ws.next().await {
+                Some(Ok(Message::Ping(_))) => pings += 1,
+                Some(Ok(Message::Text(_))) => {}
+                other => panic!("Listening client was closed: {other:?}"),
+            }
+        }
+        assert!(pings >= 5, "Only {pings} pings");
+        assert!(!handle.is_finished());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_registry() -> anyhow::Result<()> {
+        use std::sync::Arc;
+
+        use serde_json::json;
+
+        use crate::{jsonrpc::Response, Registry, TestSession};
+
+        let registry = Registry::new();
+        let builder = || {
+            let mut builder = HandlerBuilder::default();
+            builder.registry(&registry).add_raw_listener(
+                "Test.builtin",
+                Arc::new(|req: Request, _| Response::reply(&req, json!({}))),
+            );
+            builder
+        };
+
+        // One backend for every session, answering out of order.
+        let (f_in, mut f_out) = Forwarder::new(["Echo."]).split();
+        registry.forward(f_in);
+        tokio::spawn(async move {
+            let mut requests = vec![];
+            while let Some((req, _)) = f_out.incoming().recv().await {
+                requests.push(req);
+                if requests.len() < 2 {
+                    continue;
+                }
+
+                for req in requests.drain(..).rev() {
+                    let reply = Response {
+                        id: req.id,
+                        result: Some(req.params),
+                        ..Default::default()
+                    };
+                    f_out.outbound().send(reply).await.unwrap();
+                }
+            }
+        });
+
+        let (mut a, mut b) = (TestSession::new(builder()), TestSession::new(builder()));
+        assert_eq!(registry.sessions(), 2);
+
+        // Both with id 1.
+        let (from_a, from_b) = tokio::join!(
+            a.send_raw("Echo.me", json!({ "from": "a" })),
+            b.send_raw("Echo.me", json!({ "from": "b" })),
+        );
+        assert_eq!(from_a.id, Some(1.into()));
+        assert_eq!(from_a.result.unwrap()["from"], "a");
+        assert_eq!(from_b.id, Some(1.into()));
+        assert_eq!(from_b.result.unwrap()["from"], "b");
+        // The backend doesn't say what it's replying to, but the logs need to know.
+        assert_eq!(from_a.method.as_deref(), Some("Echo.me"));
+
+        registry.add_listener(protocol::dom::GetDocument, |_, _| {
+            Ok(GetDocumentReturns {
+                root: Node::default(),
+            })
+        });
+        let res = a.send_raw("DOM.getDocument", json!({})).await;
+        assert!(res.error.is_none());
+
+        // Gone from live sessions, and from ones built afterwards.
+        registry.remove_listener("test.BUILTIN").unforward("Echo.");
+        let mut c = TestSession::new(builder());
+        for session in [&mut a, &mut c] {
+            for method in ["Test.builtin", "Echo.me"] {
+                let res = session.send_raw(method, json!({})).await;
+                assert!(res.error.is_some(), "{method} should be gone");
+            }
+
+            let methods = session.handler().supported_methods();
+            assert!(!methods.contains(&"Test.builtin".to_string()));
+        }
+
+        let res = c.send_raw("DOM.getDocument", json!({})).await;
+        assert!(res.error.is_none());
+
+        drop(b);
+        assert_eq!(registry.sessions(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use serde_json::json;
+
+        use crate::{jsonrpc::Response, Backpressure, Channels, SessionOptions};
+
+        for backpressure in [
+            Backpressure::Block,
+            Backpressure::DropOldest,
+            Backpressure::Coalesce,
+        ] {
+            let (sent_tx, sent) = tokio::sync::oneshot::channel();
+            let mut builder = HandlerBuilder::default();
+            builder.on_start(move |tx| {
+                let _ = sent_tx.send(tokio::spawn(async move {
+                    for n in 0..50 {
+                        let tick = Response {
+                            method: Some("Test.tick".to_string()),
+                            params: Some(json!({ "n": n })),
+                            result: None,
+                            id: None,
+                            ..Default::default()
+                        };
+                        tx.send(tick).await.unwrap();
+                    }
+                }));
+            });
+
+            let options = SessionOptions {
+                channels: Channels {
+                    replies: 4,
+                    events: 4,
+                    backpressure,
+                    ..Default::default()
+                },
+                ..quiet()
+            };
+            // Barely room for a message, and nobody reading.
+            let mut client = PipeClient::with_buffer(builder, options, "TEST-1", 64);
+
+            let mut sending = sent.await?;
+            let finished = tokio::time::timeout(Duration::from_millis(200), &mut sending).await;
+            assert_eq!(
+                finished.is_ok(),
+                backpressure != Backpressure::Block,
+                "{backpressure:?} should only block senders when told to"
+            );
+
+            client
+                .send(r#"{"id":1,"method":"Schema.getDomains"}"#)
+                .await?;
+
+            // Up to the reply, and (for senders left waiting) whatever's sent after it.
+            let (mut ticks, mut replied) = (vec![], false);
+            while !replied || (backpressure == Backpressure::Block && ticks.len() < 50) {
+                let res = client.next().await?.unwrap();
+                match res.id {
+                    Some(_) => replied = true,
+                    None => ticks.push(res.params.unwrap()["n"].as_u64().unwrap()),
+                }
+            }
+
+            match backpressure {
+                Backpressure::Block => assert_eq!(ticks, (0..50).collect::<Vec<_>>()),
+                _ => {
+                    assert!(ticks.len() < 50, "{backpressure:?} dropped nothing");
+                    assert_eq!(ticks.last(), Some(&49));
+                    assert!(ticks.windows(2).all(|w| w[0] < w[1]));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unread_replies() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use crate::{Channels, SessionOptions};
+
+        let options = SessionOptions {
+            channels: Channels {
+                requests: 4,
+                replies: 4,
+                ..Default::default()
+            },
+            ..quiet()
+        };
+        // Barely room for a message, and nobody reading the replies.
+        let mut client = PipeClient::with_buffer(HandlerBuilder::default(), options, "TEST-1", 64);
+
+        // Once a few replies are waiting, we stop reading requests, rather than queue up forever.
+        let sending = async {
+            for id in 0..1000 {
+                let req = format!(r#"{{"id":{id},"method":"Schema.getDomains"}}"#);
+                client.send(req).await?;
+            }
+            anyhow::Ok(())
+        };
+        assert!(tokio::time::timeout(Duration::from_millis(200), sending)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_event_order() -> anyhow::Result<()> {
+        use std::sync::Arc;
+
+        use serde_json::json;
+
+        use crate::{jsonrpc::Response, traffic::dispatch_event};
+
+        let mut builder = HandlerBuilder::default();
+        builder.add_raw_listener(
+            "Runtime.enable",
+            Arc::new(|req: Request, tx| {
+                dispatch_event(
+                    &tx,
+                    Response {
+                        method: Some("Runtime.executionContextCreated".to_string()),
+                        params: Some(json!({ "context": { "id": 1 } })),
+                        result: None,
+                        id: None,
+                        ..Default::default()
+                    },
+                );
+                Response::reply(&req, json!({}))
+            }),
+        );
+
+        let mut client = PipeClient::new(builder, quiet(), "TEST-1");
+        client.send(r#"{"id":1,"method":"Runtime.enable"}"#).await?;
+
+        let event = client.next().await?.unwrap();
+        assert_eq!(
+            event.method.as_deref(),
+            Some("Runtime.executionContextCreated")
+        );
+        assert_eq!(client.next().await?.unwrap().id, Some(1.into()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recorder() -> anyhow::Result<()> {
+        use serde_json::json;
+
+        use crate::{
+            record::{self, Direction},
+            Recorder, SessionInfo,
+        };
+
+        let dir = std::env::temp_dir().join(format!("remote-debug-record-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("trace.jsonl");
+
+        // Small enough that every entry starts a new file.
+        let recorder = Recorder::new(&path)?.rotate(16, 2);
+        let session = SessionInfo::new("TEST-1");
+        for n in 0..5 {
+            recorder.record(&session, Direction::Event, &json!({ "n": n }));
+        }
+        recorder.flush();
+
+        let ns = |path| -> anyhow::Result<Vec<serde_json::Value>> {
+            Ok(record::read(path)?
+                .into_iter()
+                .map(|entry| entry.message["n"].clone())
+                .collect())
+        };
+        assert_eq!(ns(path.clone())?, [json!(4)]);
+        assert_eq!(ns(record::rotated(&path, 1))?, [json!(3)]);
+        assert_eq!(ns(record::rotated(&path, 2))?, [json!(2)]);
+        assert!(!record::rotated(&path, 3).exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay() -> anyhow::Result<()> {
+        use serde_json::json;
+
+        use crate::{record::Entry, Replay, TestSession};
+
+        let entries = [
+            json!({ "timestamp": 0, "session": 1, "target": "TEST-1", "direction": "request",
+                    "message": { "id": 7, "method": "DOM.getDocument", "params": { "depth": 1 } } }),
+            json!({ "timestamp": 1, "session": 1, "target": "TEST-1", "direction": "reply",
+                    "message": { "id": 7, "method": "DOM.getDocument", "result": { "root": "first" } } }),
+            json!({ "timestamp": 2, "session": 1, "target": "TEST-1", "direction": "event",
+                    "message": { "method": "DOM.documentUpdated", "params": {} } }),
+            json!({ "timestamp": 3, "session": 1, "target": "TEST-1", "direction": "request",
+                    "message": { "id": 8, "method": "DOM.getDocument", "params": { "depth": 1 } } }),
+            json!({ "timestamp": 4, "session": 1, "target": "TEST-1", "direction": "reply",
+                    "message": { "id": 8, "method": "DOM.getDocument", "result": { "root": "second" } } }),
+        ];
+
+        // Requests with different params, interleaved.
+        let interleaved = [(1, "a"), (2, "b"), (1, "c")].into_iter().enumerate();
+        let interleaved = interleaved.flat_map(|(id, (node_id, node))| {
+            [
+                json!({ "timestamp": 5, "session": 1, "target": "TEST-1", "direction": "request",
+                        "message": { "id": id, "method": "DOM.describeNode", "params": { "nodeId": node_id } } }),
+                json!({ "timestamp": 5, "session": 1, "target": "TEST-1", "direction": "reply",
+                        "message": { "id": id, "method": "DOM.describeNode", "result": { "node": node } } }),
+            ]
+        });
+
+        let entries = entries
+            .into_iter()
+            .chain(interleaved)
+            .map(serde_json::from_value::<Entry>)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let replay = Replay::from_entries(entries, None)?;
+        assert_eq!(replay.target(), "TEST-1");
+
+        let mut session = TestSession::new(replay.handler_builder());
+
+        for expected in ["first", "second", "second"] {
+            let res = session
+                .send_raw("DOM.getDocument", json!({ "depth": 1 }))
+                .await;
+            assert_eq!(res.result.unwrap()["root"], expected);
+        }
+
+        let res = session.send_raw("DOM.getDocument", json!({})).await;
+        assert!(res.error.is_some());
+
+        // Each takes the earliest reply left for its params.
+        for (node_id, expected) in [(2, "b"), (1, "a"), (1, "c")] {
+            let params = json!({ "nodeId": node_id });
+            let res = session.send_raw("DOM.describeNode", params).await;
+            assert_eq!(res.result.unwrap()["node"], expected);
+        }
+
+        let event = session.next_event().await.expect("No event replayed");
+        assert_eq!(event.method.as_deref(), Some("DOM.documentUpdated"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redactor() -> anyhow::Result<()> {
+        use serde_json::json;
+
+        use crate::{redact::REDACTED, Redactor};
+
+        let redactor = Redactor::new()
+            .method("Storage.")
+            .pointer("/headers/Authorization")
+            .pattern("secret-[a-z]+")?
+            .exclude("Network.dataReceived");
+
+        assert!(redactor.allows("Network.requestWillBeSent"));
+        assert!(!redactor.allows("Network.dataReceived"));
+
+        let mut params = json!({
+            "headers": { "Authorization": "Bearer abc", "Accept": "*/*" },
+            "url": "https://example.com/?token=secret-xyz",
+        });
+        redactor.redact("Network.requestWillBeSent", &mut params);
+        assert_eq!(params["headers"]["Authorization"], REDACTED);
+        assert_eq!(params["headers"]["Accept"], "*/*");
+        assert_eq!(params["url"], format!("https://example.com/?token={REDACTED}"));
+
+        let mut result = json!({ "cookies": [] });
+        redactor.redact("Storage.getCookies", &mut result);
+        assert_eq!(result, REDACTED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind() -> anyhow::Result<()> {
+        use std::net::Ipv4Addr;
+
+        use crate::Discovery;
+
+        let start = || {
+            DevToolsServer::new(
+                BrowserVersion::default(),
+                vec![Target::default()],
+                0,
+                Box::new(HandlerBuilder::default),
+                None,
+            )
+            .with_bind([Ipv4Addr::LOCALHOST])
+            .start()
+        };
+
+        // Side by side, on different ephemeral ports.
+        let (a, b) = (start().await?, start().await?);
+        let addr = a.http_addrs()[0];
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+        assert_ne!(addr.port(), b.http_addrs()[0].port());
+        assert!(a.https_addrs().is_empty());
+
+        let targets = Discovery::new(format!("http://{addr}")).targets().await?;
+        assert_eq!(targets.len(), 1);
+        // Wherever we ended up, not where the target was configured to be.
+        let url = format!("ws://{addr}/devtools/page/TEST-1");
+        assert_eq!(targets[0].web_socket_debugger_url(), url);
+
+        a.shutdown().await;
+        b.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> anyhow::Result<()> {
+        use std::net::Ipv4Addr;
+
+        use crate::{Auth, DevToolsClient, Discovery};
+
+        let handle = DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![Target::default()],
+            0,
+            Box::new(HandlerBuilder::default),
+            None,
+        )
+        .with_bind([Ipv4Addr::LOCALHOST])
+        .with_auth(Auth::new().shared("s3cret"))
+        .start()
+        .await?;
+
+        let addr = handle.http_addrs()[0];
+        let page = format!("ws://{addr}/devtools/page/TEST-1");
+
+        // Only authenticated requesters get the token back.
+        let base = format!("http://{addr}");
+        let listed = Discovery::new(&base).targets().await?;
+        assert!(!listed[0].web_socket_debugger_url().contains("token"));
+        let listed: Vec<Target> = serde_json::from_slice(
+            &hyper::body::to_bytes(
+                hyper::Client::new()
+                    .get(format!("{base}/json/list?token=s3cret").parse()?)
+                    .await?
+                    .into_body(),
+            )
+            .await?,
+        )?;
+        assert!(listed[0]
+            .web_socket_debugger_url()
+            .ends_with("?token=s3cret"));
+
+        assert!(DevToolsClient::connect(&page).await.is_err());
+        DevToolsClient::connect(&format!("{page}?token=s3cret")).await?;
+
+        handle.auth().set_shared(Some("rotated".to_string()));
+        assert!(DevToolsClient::connect(&format!("{page}?token=s3cret"))
+            .await
+            .is_err());
+        DevToolsClient::connect(&format!("{page}?token=rotated")).await?;
+
+        handle.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_target() -> anyhow::Result<()> {
+        use crate::Auth;
+
+        // Only TEST-1 has a token, so a made-up id mustn't get in without one.
+        let server = DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![Target::default()],
+            0,
+            Box::new(HandlerBuilder::default),
+            None,
+        )
+        .with_auth(Auth::new().target("TEST-1", "t0k"));
+        let routes = server.routes(None);
+
+        let connect = |path: &str| warp::test::ws().path(path).handshake(routes.clone());
+        assert!(connect("/devtools/page/ANYTHING").await.is_err());
+        assert!(connect("/devtools/page/TEST-1").await.is_err());
+        assert!(connect("/devtools/page/TEST-1?token=t0k").await.is_ok());
+
+        let res = warp::test::request()
+            .path("/devtools/page/ANYTHING")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 404);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_self_signed() -> anyhow::Result<()> {
+        use std::net::Ipv4Addr;
+
+        use crate::SelfSigned;
+
+        let dir = std::env::temp_dir().join(format!("remote-debug-certs-{}", std::process::id()));
+        let tls = SelfSigned::new()
+            .host("devtools.internal")
+            .persist(&dir)
+            .generate(0)?;
+
+        // 32 bytes, as hex pairs.
+        assert_eq!(tls.fingerprint().unwrap().split(':').count(), 32);
+        assert!(std::fs::read_to_string(dir.join("cert.pem"))?
+            .starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(dir.join("key.pem").exists());
+
+        let handle = DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![Target::default()],
+            0,
+            Box::new(HandlerBuilder::default),
+            tls,
+        )
+        .with_bind([Ipv4Addr::LOCALHOST])
+        .without_http()
+        .start()
+        .await?;
+        assert_eq!(handle.https_addrs().len(), 1);
+        assert!(handle.http_addrs().is_empty());
+
+        handle.shutdown().await;
+        std::fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload_tls() -> anyhow::Result<()> {
+        use std::{io::BufReader, net::Ipv4Addr, sync::Arc};
+
+        use tokio_rustls::{
+            rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+            TlsConnector,
+        };
+
+        use crate::{certs::fingerprint, Pem, SelfSigned};
+
+        let first = SelfSigned::new().generate(0)?;
+        let second = SelfSigned::new().generate(0)?;
+
+        let mut roots = RootCertStore::empty();
+        for tls in [&first, &second] {
+            let Pem::Bytes(ref pem) = tls.certificate else {
+                unreachable!()
+            };
+            for cert in rustls_pemfile::certs(&mut BufReader::new(&pem[..])) {
+                roots.add(cert?)?;
+            }
+        }
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let first_print = first.fingerprint().unwrap().to_string();
+        let handle = DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![Target::default()],
+            0,
+            Box::new(HandlerBuilder::default),
+            first,
+        )
+        .with_bind([Ipv4Addr::LOCALHOST])
+        .start()
+        .await?;
+        let addr = handle.https_addrs()[0];
+
+        // Fingerprint of whatever the server presents.
+        let presented = || async {
+            let tcp = tokio::net::TcpStream::connect(addr).await?;
+            let tls = connector
+                .connect(ServerName::try_from("localhost")?, tcp)
+                .await?;
+            let cert = &tls.get_ref().1.peer_certificates().unwrap()[0];
+            anyhow::Ok(fingerprint(cert))
+        };
+
+        assert_eq!(presented().await?, first_print);
+
+        handle.replace_tls(second.certificate.clone(), second.private_key.clone())?;
+        assert_eq!(presented().await?, second.fingerprint().unwrap());
+
+        handle.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_ca() -> anyhow::Result<()> {
+        use std::{io::BufReader, net::Ipv4Addr, sync::Arc};
+
+        use rcgen::{
+            BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa,
+            KeyPair,
+        };
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::{
+            rustls::{
+                pki_types::{PrivateKeyDer, ServerName},
+                ClientConfig, RootCertStore,
+            },
+            TlsConnector,
+        };
+
+        use crate::{Pem, SelfSigned};
+
+        fn ca() -> anyhow::Result<(Certificate, KeyPair)> {
+            let key = KeyPair::generate()?;
+            let mut params = CertificateParams::new(vec![])?;
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            Ok((params.self_signed(&key)?, key))
+        }
+
+        let (ca_cert, ca_key) = ca()?;
+        let (other_cert, other_key) = ca()?;
+
+        let tls = SelfSigned::new().generate(0)?;
+        let mut roots = RootCertStore::empty();
+        let Pem::Bytes(ref pem) = tls.certificate else {
+            unreachable!()
+        };
+        for cert in rustls_pemfile::certs(&mut BufReader::new(&pem[..])) {
+            roots.add(cert?)?;
+        }
+
+        let handle = DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![Target::default()],
+            0,
+            Box::new(HandlerBuilder::default),
+            tls.with_client_ca(Pem::bytes(ca_cert.pem())),
+        )
+        .with_bind([Ipv4Addr::LOCALHOST])
+        .start()
+        .await?;
+        let addr = handle.https_addrs()[0];
+        // Plain HTTP would go around the client certificate check.
+        assert!(handle.http_addrs().is_empty());
+
+        // Whether `/json/version` gets answered, presenting a certificate signed by `ca`, if any.
+        let served = |ca: Option<(&Certificate, &KeyPair)>| {
+            let builder = ClientConfig::builder().with_root_certificates(roots.clone());
+            let config = match ca {
+                Some((ca_cert, ca_key)) => {
+                    let key = KeyPair::generate().unwrap();
+                    let mut params = CertificateParams::new(vec!["client".to_string()]).unwrap();
+                    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+                    let cert = params.signed_by(&key, ca_cert, ca_key).unwrap();
+                    let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+                    builder
+                        .with_client_auth_cert(vec![cert.der().clone()], key)
+                        .unwrap()
+                }
+                None => builder.with_no_client_auth(),
+            };
+            let connector = TlsConnector::from(Arc::new(config));
+
+            async move {
+                let attempt = async {
+                    let tcp = tokio::net::TcpStream::connect(addr).await?;
+                    let mut tls = connector
+                        .connect(ServerName::try_from("localhost")?, tcp)
+                        .await?;
+                    tls.write_all(b"GET /json/version HTTP/1.1\r\n").await?;
+                    tls.write_all(b"Host: localhost\r\nConnection: close\r\n\r\n")
+                        .await?;
+                    let mut res = vec![];
+                    tls.read_to_end(&mut res).await?;
+                    anyhow::Ok(res.starts_with(b"HTTP/1.1 200"))
+                };
+                // With TLS 1.3, a refused certificate only shows up once we read.
+                attempt.await.unwrap_or(false)
+            }
+        };
+
+        assert!(!served(None).await);
+        assert!(!served(Some((&other_cert, &other_key))).await);
+        assert!(served(Some((&ca_cert, &ca_key))).await);
+
+        handle.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_roles() -> anyhow::Result<()> {
+        use crate::{Auth, Role, TestSession};
+
+        let observer = Role::new("observer")
+            .allow("Schema.getDomains")
+            .allow("Runtime.")
+            .deny("Runtime.evaluate");
+
+        let auth = Auth::new().shared("admin").role("viewer", observer);
+        assert!(auth.allows("TEST-1", Some("viewer")));
+        assert!(auth.role_of(Some("admin")).is_none());
+
+        let mut builder = HandlerBuilder::default();
+        builder.role(auth.role_of(Some("viewer")).unwrap());
+        let mut session = TestSession::new(builder);
+
+        let res = session
+            .send_raw("Schema.getDomains", serde_json::json!({}))
+            .await;
+        assert!(res.error.is_none());
+
+        for method in ["Runtime.evaluate", "DOM.setAttributeValue"] {
+            let res = session.send_raw(method, serde_json::json!({})).await;
+            assert!(res.result.is_none());
+            assert_eq!(res.error.map(|e| e.code), Some(-32000));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowlist() {
+        use crate::Allowlist;
+
+        let allow = Allowlist::new()
+            .origin("https://*.example.com")
+            .host("devtools.internal");
+
+        assert!(allow.allows_origin(None));
+        assert!(allow.allows_origin(Some("https://app.example.com")));
+        assert!(!allow.allows_origin(Some("https://example.com.evil.net")));
+        assert!(!allow.allows_origin(Some("http://localhost:3000")));
+        assert!(Allowlist::new().allows_origin(Some("devtools://devtools")));
+        assert!(!Allowlist::new().allows_origin(Some("devtools://evil")));
+
+        assert!(allow.allows_host(Some("localhost:9222")));
+        assert!(allow.allows_host(Some("127.0.0.1:9222")));
+        assert!(allow.allows_host(Some("[::1]:9222")));
+        assert!(allow.allows_host(Some("DevTools.internal:9222")));
+        assert!(!allow.allows_host(Some("rebound.evil.net:9222")));
+    }
+
+    #[tokio::test]
+    async fn test_routes() -> anyhow::Result<()> {
+        use warp::Filter;
+
+        let server = DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![Target::default()],
+            0,
+            Box::new(HandlerBuilder::default),
+            None,
+        );
+
+        let app = warp::path!("hello").map(|| "Hello!");
+        let routes = server.routes(Some("/tools/debug/")).or(app);
+
+        let res = warp::test::request()
+            .path("/tools/debug/json/list")
+            .header("host", "localhost:8080")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+        let targets: Vec<Target> = serde_json::from_slice(res.body())?;
+        assert_eq!(targets[0].id(), "TEST-1");
+        let url = "ws://localhost:8080/tools/debug/devtools/page/TEST-1";
+        assert_eq!(targets[0].web_socket_debugger_url(), url);
+
+        // Not under the prefix.
+        let res = warp::test::request()
+            .path("/json/list")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 404);
+
+        // Only our own routes check the Origin.
+        let res = warp::test::request()
+            .path("/hello")
+            .header("origin", "https://evil.example")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .path("/tools/debug/json/version")
+            .header("origin", "https://evil.example")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 403);
+
+        // Chrome's own frontend is let in without asking.
+        let res = warp::test::request()
+            .path("/tools/debug/json/version")
+            .header("origin", "devtools://devtools")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_protocol() -> anyhow::Result<()> {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        use crate::{Pipe, Registry};
+
+        async fn domains<F>(routes: &F) -> Vec<String>
+        where
+            F: warp::Filter + 'static,
+            F::Extract: warp::Reply + Send,
+        {
+            let res = warp::test::request()
+                .path("/json/protocol")
+                .reply(routes)
+                .await;
+            let protocol: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            protocol["domains"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|d| d["domain"].as_str().unwrap().to_string())
+                .collect()
+        }
+
+        let registry = Registry::new();
+        let built = Arc::new(AtomicUsize::new(0));
+        let version = BrowserVersion {
+            protocol_version: "1.2".to_string(),
+            ..Default::default()
+        };
+
+        let factory = {
+            let (registry, built) = (registry.clone(), built.clone());
+            move || {
+                built.fetch_add(1, Ordering::SeqCst);
+                let mut builder = HandlerBuilder::default();
+                builder.registry(&registry);
+                builder
+            }
+        };
+        let server = Arc::new(DevToolsServer::new(
+            version,
+            vec![Target::default()],
+            0,
+            Box::new(factory),
+            None,
+        ));
+        let routes = server.routes(None);
+
+        // Nothing's attached yet, and we don't start a session just to find out.
+        assert!(domains(&routes).await.is_empty());
+        assert_eq!(built.load(Ordering::SeqCst), 0);
+
+        let (ours, theirs) = tokio::io::duplex(64 << 10);
+        let session = tokio::spawn({
+            let server = server.clone();
+            let (read, write) = tokio::io::split(ours);
+            async move { server.serve(Pipe::new(read, write), "TEST-1").await }
+        });
+
+        let mut client = PipeClient::over(theirs);
+        client
+            .send(r#"{"id":1,"method":"Schema.getDomains"}"#)
+            .await?;
+        let res = client.next().await?.unwrap();
+        assert_eq!(res.result.unwrap()["domains"][0]["version"], "1.2");
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        assert_eq!(domains(&routes).await, ["Schema"]);
+
+        // Keeps up with the live sessions.
+        registry.add_listener(protocol::dom::GetDocument, |_, _| {
+            Ok(GetDocumentReturns {
+                root: Node::default(),
+            })
+        });
+        assert_eq!(domains(&routes).await, ["DOM", "Schema"]);
+
+        // And remembers them once they've gone.
+        drop(client);
+        session.await?;
+        assert_eq!(domains(&routes).await, ["DOM", "Schema"]);
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics() -> anyhow::Result<()> {
+        use crate::{Metrics, SessionOptions};
+
+        let metrics = Metrics::new();
+        let options = SessionOptions {
+            metrics: Some(metrics.clone()),
+            ..quiet()
+        };
+
+        let mut client = PipeClient::new(HandlerBuilder::default(), options.clone(), "TEST-1");
+        client
+            .send(r#"{"id":1,"method":"Schema.getDomains"}"#)
+            .await?;
+        client.send(r#"{"id":2,"method":"Nope.nope"}"#).await?;
+        client.next().await?;
+        client.next().await?;
+
+        let text = metrics.render();
+        assert!(text.contains("remote_debug_sessions{target=\"TEST-1\"} 1"));
+        assert!(text.contains("remote_debug_requests_total{method=\"Schema.getDomains\"} 1"));
+        assert!(text.contains("remote_debug_errors_total{code=\"-32600\"} 1"));
+
+        // Unsupported methods don't get a label of their own.
+        assert!(text.contains("remote_debug_requests_total{method=\"other\"} 1"));
+        assert!(!text.contains("Nope.nope"));
+
+        // Every family's lines come together.
+        let totals = text.rfind("remote_debug_requests_total{").unwrap();
+        let histogram = text.find("remote_debug_request_duration_seconds").unwrap();
+        assert!(totals < histogram);
+
+        // A forwarder shared between sessions is only counted once.
+        let registry = crate::Registry::new();
+        let (f_in, _f_out) = crate::util::Forwarder::new(["Echo."]).split();
+        registry.forward(f_in);
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let mut builder = HandlerBuilder::default();
+            builder.registry(&registry);
+            let mut client = PipeClient::new(builder, options.clone(), "TEST-2");
+            client
+                .send(r#"{"id":1,"method":"Schema.getDomains"}"#)
+                .await?;
+            client.next().await?;
+            clients.push(client);
+        }
+
+        // Never picked up, so it stays queued.
+        clients[0].send(r#"{"id":2,"method":"Echo.echo"}"#).await?;
+        let depth = "remote_debug_forwarder_queue_depth{forwarder=\"Echo.\"}";
+        let text = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                let text = metrics.render();
+                if !text.contains(&format!("{depth} 0")) {
+                    break text;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        assert!(text.contains(&format!("{depth} 1")));
+
+        Ok(())
+    }
 }
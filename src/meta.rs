@@ -6,13 +6,18 @@
 //!
 //!
 
-use crate::server::DevToolsServer;
+use std::collections::BTreeMap;
+
+use serde_json::json;
+
+use crate::util;
 
 ///
 /// Targets listsed in under this DevTools Server
 ///
-#[derive(Debug, serde::Serialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct Target {
+    #[serde(default)]
     pub(crate) description: String,
 
     #[serde(rename = "devtoolsFrontendUrl")]
@@ -22,13 +27,74 @@ pub struct Target {
 
     #[serde(rename = "type")]
     pub(crate) target_type: String,
+
+    #[serde(default)]
     pub(crate) url: String,
 
-    #[serde(rename = "webSocketDebuggerUrl")]
+    ///
+    /// Chrome leaves this out for targets that already have a client attached.
+    ///
+    #[serde(rename = "webSocketDebuggerUrl", default)]
     pub(crate) web_socket_debugger_url: String,
 
     #[serde(rename = "faviconUrl")]
     pub(crate) favicon_url: Option<String>,
+
+    ///
+    /// Whether any client has a session open to this target.
+    ///
+    #[serde(default)]
+    pub(crate) attached: bool,
+}
+
+impl Target {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn target_type(&self) -> &str {
+        &self.target_type
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn web_socket_debugger_url(&self) -> &str {
+        &self.web_socket_debugger_url
+    }
+
+    pub fn attached(&self) -> bool {
+        self.attached
+    }
+
+    ///
+    /// This target as Chrome would list it to a client that reached us at `host`
+    /// (its `Host` header), with sessions under `pages` (e.g. `/devtools/page`).
+    /// Only the scheme (`ws` or `wss`) of the configured URL is kept.
+    ///
+    pub(crate) fn served_at(mut self, host: &str, pages: &str) -> Self {
+        let scheme = match self.web_socket_debugger_url.starts_with("wss:") {
+            true => "wss",
+            false => "ws",
+        };
+        let page = format!("{host}{pages}/{}", self.id);
+
+        self.web_socket_debugger_url = format!("{scheme}://{page}");
+        if self.devtools_frontend_url.is_some() {
+            self.devtools_frontend_url = Some(format!("/devtools/inspector.html?{scheme}={page}"));
+        }
+
+        self
+    }
 }
 
 impl Default for Target {
@@ -46,11 +112,12 @@ impl Default for Target {
             favicon_url: "https://www.google.com/favicon.ico".to_string().into(),
             url: "test://remote-debug".to_string(),
             web_socket_debugger_url: "ws://localhost:9002/devtools/page/TEST-1".to_string(),
+            attached: false,
         }
     }
 }
 
-#[derive(Debug, serde::Serialize, Clone)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct BrowserVersion {
     ///
     /// Format: `${NAME}/${VERSION}`
@@ -63,7 +130,7 @@ pub struct BrowserVersion {
     #[serde(rename = "Protocol-Version")]
     pub(crate) protocol_version: String,
 
-    #[serde(rename = "User-Agent")]
+    #[serde(rename = "User-Agent", default)]
     pub(crate) user_agent: String,
 
     #[serde(rename = "V8-Version")]
@@ -76,6 +143,24 @@ pub struct BrowserVersion {
     pub(crate) web_socket_debugger_url: Option<String>,
 }
 
+impl BrowserVersion {
+    pub fn browser(&self) -> &str {
+        &self.browser
+    }
+
+    pub fn protocol_version(&self) -> &str {
+        &self.protocol_version
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn web_socket_debugger_url(&self) -> Option<&str> {
+        self.web_socket_debugger_url.as_deref()
+    }
+}
+
 impl Default for BrowserVersion {
     fn default() -> Self {
         BrowserVersion {
@@ -96,19 +181,71 @@ impl Default for BrowserVersion {
 pub enum MetaOperation {
     Targets,
     Version,
+    Protocol,
 }
 
 impl MetaOperation {
-    pub fn exec(&self, version: &BrowserVersion, targets: &[Target]) -> serde_json::Value {
+    pub fn exec(
+        &self,
+        version: &BrowserVersion,
+        targets: &[Target],
+        methods: &[String],
+    ) -> serde_json::Value {
         match self {
             MetaOperation::Targets => serde_json::to_value(targets)
                 .expect("Server.targets failed to be serialized!"),
             MetaOperation::Version => serde_json::to_value(version)
                 .expect("Server.version failed to be serialized!"),
+            MetaOperation::Protocol => protocol(version, methods),
         }
     }
 }
 
+///
+/// A (very) cut-down `/json/protocol`, listing only the domains
+/// and commands we actually support.
+///
+/// Forwarded domains show up without any commands, as we
+/// can't know what's on the other end.
+///
+pub fn protocol(version: &BrowserVersion, methods: &[String]) -> serde_json::Value {
+    let mut domains: BTreeMap<String, Vec<serde_json::Value>> = util::domains(
+        methods.iter().map(String::as_str),
+    )
+    .into_iter()
+    .map(|d| (d, vec![]))
+    .collect();
+
+    for method in methods {
+        let domain = util::domain_of(method);
+        let name = method[domain.len()..].trim_start_matches('.');
+
+        if name.is_empty() {
+            continue;
+        }
+
+        if let Some((_, commands)) = domains
+            .iter_mut()
+            .find(|(d, _)| d.eq_ignore_ascii_case(domain))
+        {
+            commands.push(json!({ "name": name }));
+        }
+    }
+
+    let (major, minor) = version
+        .protocol_version
+        .split_once('.')
+        .unwrap_or((&version.protocol_version, "0"));
+
+    json!({
+        "version": { "major": major, "minor": minor },
+        "domains": domains
+            .into_iter()
+            .map(|(domain, commands)| json!({ "domain": domain, "commands": commands }))
+            .collect::<Vec<_>>(),
+    })
+}
+
 impl TryFrom<String> for MetaOperation {
     type Error = ();
 
@@ -116,6 +253,7 @@ impl TryFrom<String> for MetaOperation {
         match value.as_str() {
             "version" => Ok(Self::Version),
             "list" => Ok(Self::Targets),
+            "protocol" => Ok(Self::Protocol),
             "" => Ok(Self::Targets),
             _ => Err(()),
         }
@@ -0,0 +1,290 @@
+//!
+//! Counters for Prometheus to scrape, at `/metrics`
+//! (see [`DevToolsServer::with_metrics`](crate::DevToolsServer::with_metrics)).
+//!
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::Duration,
+};
+
+use crate::util::{ForwarderIn, Routes};
+
+///
+/// Upper bounds (in seconds) of the request latency buckets.
+///
+const BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+///
+/// The `method` label for requests to methods we don't support.
+///
+const OTHER_METHOD: &str = "other";
+
+///
+/// Cloneable handle to a set of metrics, shared between sessions.
+///
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    ///
+    /// Target -> open sessions.
+    ///
+    sessions: Mutex<BTreeMap<String, u64>>,
+
+    ///
+    /// Method -> how long its requests took.
+    ///
+    requests: Mutex<BTreeMap<String, Latency>>,
+
+    ///
+    /// Error code -> replies with it.
+    ///
+    errors: Mutex<BTreeMap<i32, u64>>,
+
+    events: AtomicU64,
+    dropped: AtomicU64,
+
+    ///
+    /// Every session's routes, for forwarder queue depths.
+    ///
+    routes: Mutex<Vec<Weak<RwLock<Routes>>>>,
+}
+
+#[derive(Default)]
+struct Latency {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn session_started(&self, target: &str, routes: &Arc<RwLock<Routes>>) {
+        *self
+            .inner
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(target.to_string())
+            .or_default() += 1;
+
+        let mut all = self.inner.routes.lock().unwrap();
+        all.retain(|r| r.strong_count() > 0);
+        all.push(Arc::downgrade(routes));
+    }
+
+    pub(crate) fn session_ended(&self, target: &str) {
+        if let Some(open) = self.inner.sessions.lock().unwrap().get_mut(target) {
+            *open = open.saturating_sub(1);
+        }
+    }
+
+    ///
+    /// Count a request to `method`, or (with `None`) one we don't support,
+    /// which all share a label so clients can't make up endless new ones.
+    ///
+    pub(crate) fn request(&self, method: Option<&str>, elapsed: Duration, error: Option<i32>) {
+        let secs = elapsed.as_secs_f64();
+
+        {
+            let mut requests = self.inner.requests.lock().unwrap();
+            let method = method.unwrap_or(OTHER_METHOD);
+            let latency = requests.entry(method.to_string()).or_default();
+            for (bucket, le) in latency.buckets.iter_mut().zip(BUCKETS) {
+                if secs <= le {
+                    *bucket += 1;
+                }
+            }
+            latency.sum += secs;
+            latency.count += 1;
+        }
+
+        if let Some(code) = error {
+            *self.inner.errors.lock().unwrap().entry(code).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn event(&self) {
+        self.inner.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self, n: u64) {
+        self.inner.dropped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    ///
+    /// Everything, in Prometheus' text format.
+    ///
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "sessions", "gauge", "Open sessions, per target.");
+        for (target, open) in self.inner.sessions.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "remote_debug_sessions{{target=\"{}\"}} {open}",
+                escape(target)
+            );
+        }
+
+        header(
+            &mut out,
+            "requests_total",
+            "counter",
+            "Requests handled, per method.",
+        );
+        let requests = self.inner.requests.lock().unwrap();
+        for (method, latency) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "remote_debug_requests_total{{method=\"{}\"}} {}",
+                escape(method),
+                latency.count
+            );
+        }
+
+        // Each family's lines have to be together, so the histogram gets its own pass.
+        header(
+            &mut out,
+            "request_duration_seconds",
+            "histogram",
+            "Time taken to reply to requests, per method.",
+        );
+        for (method, latency) in requests.iter() {
+            let method = escape(method);
+            for (count, le) in latency.buckets.iter().zip(BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "remote_debug_request_duration_seconds_bucket{{method=\"{method}\",le=\"{le}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "remote_debug_request_duration_seconds_bucket{{method=\"{method}\",le=\"+Inf\"}} {}",
+                latency.count
+            );
+            let _ = writeln!(
+                out,
+                "remote_debug_request_duration_seconds_sum{{method=\"{method}\"}} {}",
+                latency.sum
+            );
+            let _ = writeln!(
+                out,
+                "remote_debug_request_duration_seconds_count{{method=\"{method}\"}} {}",
+                latency.count
+            );
+        }
+        drop(requests);
+
+        header(
+            &mut out,
+            "errors_total",
+            "counter",
+            "Error replies, per error code.",
+        );
+        for (code, count) in self.inner.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "remote_debug_errors_total{{code=\"{code}\"}} {count}");
+        }
+
+        header(
+            &mut out,
+            "forwarder_queue_depth",
+            "gauge",
+            "Requests waiting on forwarders, per forwarder.",
+        );
+        for (actions, depth) in self.forwarder_depths() {
+            let _ = writeln!(
+                out,
+                "remote_debug_forwarder_queue_depth{{forwarder=\"{}\"}} {depth}",
+                escape(&actions)
+            );
+        }
+
+        header(
+            &mut out,
+            "events_total",
+            "counter",
+            "Events sent to clients.",
+        );
+        let _ = writeln!(
+            out,
+            "remote_debug_events_total {}",
+            self.inner.events.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "events_dropped_total",
+            "counter",
+            "Events dropped (or coalesced) for slow clients.",
+        );
+        let _ = writeln!(
+            out,
+            "remote_debug_events_dropped_total {}",
+            self.inner.dropped.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+
+    ///
+    /// Forwarder (by its actions) -> queued requests, across all live sessions.
+    ///
+    /// Forwarders shared between sessions (e.g. through a [`Registry`](crate::Registry))
+    /// are only counted once.
+    ///
+    fn forwarder_depths(&self) -> BTreeMap<String, usize> {
+        let mut depths = BTreeMap::new();
+        let mut seen: Vec<Arc<ForwarderIn>> = vec![];
+        let mut all = self.inner.routes.lock().unwrap();
+        all.retain(|r| r.strong_count() > 0);
+
+        for routes in all.iter().filter_map(Weak::upgrade) {
+            for forwarder in routes.read().unwrap().forwarders.iter() {
+                if seen.iter().any(|f| Arc::ptr_eq(f, forwarder)) {
+                    continue;
+                }
+                seen.push(forwarder.clone());
+
+                *depths.entry(forwarder.actions().join(",")).or_default() += forwarder.queued();
+            }
+        }
+
+        depths
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP remote_debug_{name} {help}");
+    let _ = writeln!(out, "# TYPE remote_debug_{name} {kind}");
+}
+
+///
+/// Escape a label value.
+///
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
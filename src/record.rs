@@ -0,0 +1,260 @@
+//!
+//! Recording sessions to (rotating) JSON Lines files,
+//! for machine-readable protocol traces.
+//!
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::traffic::SessionInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    ///
+    /// Client -> us.
+    ///
+    Request,
+
+    ///
+    /// Us -> client, in reply to a request.
+    ///
+    Reply,
+
+    ///
+    /// Us -> client, unprompted.
+    ///
+    Event,
+}
+
+///
+/// A single line of a recording.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    ///
+    /// Milliseconds since the UNIX epoch.
+    ///
+    pub timestamp: u64,
+    pub session: u64,
+    pub target: String,
+    pub direction: Direction,
+
+    ///
+    /// The whole message, as sent/received.
+    ///
+    pub message: serde_json::Value,
+}
+
+///
+/// Cloneable handle to a recording, shared between sessions.
+///
+/// Entries are written out (and flushed, in batches) on a thread of
+/// its own, so sessions never wait on the disk.
+///
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    jobs: SyncSender<Job>,
+}
+
+///
+/// Entries waiting to be written before any more are dropped.
+///
+const QUEUED: usize = 4096;
+
+#[derive(Debug)]
+enum Job {
+    Write(Vec<u8>),
+    Rotate {
+        max_bytes: u64,
+        keep: usize,
+    },
+
+    ///
+    /// Answered once everything before it has been written and flushed.
+    ///
+    Flush(SyncSender<()>),
+}
+
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    written: u64,
+
+    ///
+    /// Rotate once the file is this big.
+    ///
+    max_bytes: Option<u64>,
+
+    ///
+    /// How many rotated files (`path.1`, `path.2`, ...) to keep.
+    ///
+    keep: usize,
+}
+
+impl Recorder {
+    ///
+    /// Record to `path`, appending if it already exists.
+    ///
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        let writer = Writer {
+            path,
+            file: BufWriter::new(file),
+            written,
+            max_bytes: None,
+            keep: 0,
+        };
+
+        let (jobs, queued) = mpsc::sync_channel(QUEUED);
+        std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || writer.run(queued))?;
+
+        Ok(Self { jobs })
+    }
+
+    ///
+    /// Start a new file once the current one reaches `max_bytes`,
+    /// keeping the last `keep` files around as `path.1` (newest), `path.2`, ...
+    ///
+    pub fn rotate(self, max_bytes: u64, keep: usize) -> Self {
+        let _ = self.jobs.send(Job::Rotate { max_bytes, keep });
+        self
+    }
+
+    ///
+    /// Wait until everything recorded so far is on disk.
+    /// This blocks, so isn't one for async code.
+    ///
+    pub fn flush(&self) {
+        let (done, flushed) = mpsc::sync_channel(1);
+        if self.jobs.send(Job::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+
+    pub fn record(&self, session: &SessionInfo, direction: Direction, message: &impl Serialize) {
+        let entry = Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            session: session.id,
+            target: session.target.clone(),
+            direction,
+            message: serde_json::to_value(message).unwrap_or_default(),
+        };
+
+        let mut line = serde_json::to_vec(&entry).expect("Could not Serialize Entry");
+        line.push(b'\n');
+
+        // Better a gap in the recording than a session stuck behind it.
+        if self.jobs.try_send(Job::Write(line)).is_err() {
+            tracing::warn!("Recording can't keep up, dropped an entry");
+        }
+    }
+}
+
+impl Writer {
+    fn run(mut self, jobs: Receiver<Job>) {
+        while let Ok(job) = jobs.recv() {
+            let mut flushed = vec![];
+
+            // Whatever else is waiting goes out with it, in one flush.
+            for job in std::iter::once(job).chain(jobs.try_iter().take(QUEUED)) {
+                match job {
+                    Job::Write(line) => {
+                        // A broken recording shouldn't take the session down with it.
+                        if let Err(e) = self.write(&line) {
+                            tracing::warn!(error = %e, "Could not record to log");
+                        }
+                    }
+                    Job::Rotate { max_bytes, keep } => {
+                        self.max_bytes = Some(max_bytes);
+                        self.keep = keep;
+                    }
+                    Job::Flush(done) => flushed.push(done),
+                }
+            }
+
+            if let Err(e) = self.file.flush() {
+                tracing::warn!(error = %e, "Could not record to log");
+            }
+
+            for done in flushed {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if let Some(max) = self.max_bytes {
+            if self.written > 0 && self.written + line.len() as u64 > max {
+                self.rotate()?;
+            }
+        }
+
+        self.file.write_all(line)?;
+        self.written += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rotated(&self.path, n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+
+        self.file = BufWriter::new(File::create(&self.path)?);
+        self.written = 0;
+
+        Ok(())
+    }
+}
+
+///
+/// Every entry in a recording, skipping any lines that don't parse.
+///
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+///
+/// `path.n`
+///
+pub fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
@@ -0,0 +1,126 @@
+//!
+//! Keeping secrets (and noise) out of traffic logs.
+//!
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::util::action_matches;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone)]
+pub enum Rule {
+    ///
+    /// Hide all params/results of a method, or a whole domain
+    /// (same format as [`Fallback::Acknowledge`](crate::Fallback::Acknowledge)).
+    ///
+    Method(String),
+
+    ///
+    /// Hide whatever is at this JSON pointer (e.g. `/headers/Authorization`),
+    /// inside params or results.
+    ///
+    Pointer(String),
+
+    ///
+    /// Hide any part of a string value matching this.
+    ///
+    Pattern(Regex),
+}
+
+///
+/// Which messages get logged, and what's hidden in them.
+///
+/// Nothing is filtered or hidden by default.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+
+    ///
+    /// If not empty, only log these methods/domains.
+    ///
+    include: Vec<String>,
+
+    ///
+    /// Never log these methods/domains.
+    ///
+    exclude: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn method(self, action: impl Into<String>) -> Self {
+        self.rule(Rule::Method(action.into()))
+    }
+
+    pub fn pointer(self, pointer: impl Into<String>) -> Self {
+        self.rule(Rule::Pointer(pointer.into()))
+    }
+
+    pub fn pattern(self, pattern: &str) -> Result<Self, regex::Error> {
+        Ok(self.rule(Rule::Pattern(Regex::new(pattern)?)))
+    }
+
+    pub fn include(mut self, action: impl Into<String>) -> Self {
+        self.include.push(action.into());
+        self
+    }
+
+    pub fn exclude(mut self, action: impl Into<String>) -> Self {
+        self.exclude.push(action.into());
+        self
+    }
+
+    ///
+    /// Whether `method` should be logged at all.
+    ///
+    pub fn allows(&self, method: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|a| action_matches(a, method)))
+            && !self.exclude.iter().any(|a| action_matches(a, method))
+    }
+
+    ///
+    /// Hide anything in `value` (a message's params or result) the rules say to.
+    ///
+    pub fn redact(&self, method: &str, value: &mut Value) {
+        for rule in self.rules.iter() {
+            match rule {
+                Rule::Method(action) => {
+                    if action_matches(action, method) {
+                        *value = Value::String(REDACTED.to_string());
+                        return;
+                    }
+                }
+                Rule::Pointer(pointer) => {
+                    if let Some(v) = value.pointer_mut(pointer) {
+                        *v = Value::String(REDACTED.to_string());
+                    }
+                }
+                Rule::Pattern(re) => redact_strings(re, value),
+            }
+        }
+    }
+}
+
+fn redact_strings(re: &Regex, value: &mut Value) {
+    match value {
+        Value::String(s) => {
+            if re.is_match(s) {
+                *s = re.replace_all(s, REDACTED).into_owned();
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| redact_strings(re, v)),
+        Value::Object(values) => values.values_mut().for_each(|v| redact_strings(re, v)),
+        _ => {}
+    }
+}
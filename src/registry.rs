@@ -0,0 +1,247 @@
+//!
+//! Adding and removing listeners/forwarders on live sessions.
+//!
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock, Weak},
+};
+
+use chrome_devtools_api::Command;
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    jsonrpc::Response,
+    traffic::dispatch_event,
+    util::{ForwarderIn, IntoRawListener, RawListener, Routes},
+};
+
+type Notification = dyn Fn(Vec<String>) -> Response + Send + Sync + 'static;
+
+///
+/// Cloneable handle to every session built from a
+/// [`HandlerBuilder`](crate::HandlerBuilder) attached to it
+/// (see [`HandlerBuilder::registry`](crate::HandlerBuilder::registry)).
+///
+/// Anything registered here applies to live sessions straight away,
+/// and to sessions that connect later on.
+///
+/// Must be used from within a Tokio runtime, if notifications are on.
+///
+#[derive(Clone, Default)]
+pub struct Registry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    ///
+    /// Lowercase id -> (original id, listener).
+    ///
+    handlers: HashMap<String, (String, Arc<RawListener>)>,
+
+    ///
+    /// Lowercase ids of listeners removed, so sessions built later
+    /// don't get them back from their [`HandlerBuilder`](crate::HandlerBuilder).
+    ///
+    removed: HashSet<String>,
+
+    ///
+    /// Shared between all sessions.
+    ///
+    forwarders: Vec<Arc<ForwarderIn>>,
+
+    ///
+    /// Actions unforwarded, likewise.
+    ///
+    unforwarded: Vec<String>,
+
+    ///
+    /// Live sessions, and their events channel.
+    ///
+    sessions: Vec<(Weak<RwLock<Routes>>, Sender<Response>)>,
+
+    ///
+    /// Notification to push to every session when the handler set changes.
+    ///
+    notification: Option<Arc<Notification>>,
+}
+
+impl Inner {
+    fn apply(&self, routes: &mut Routes) {
+        for key in self.removed.iter() {
+            routes.handlers.remove(key);
+            routes.methods.retain(|m| m.to_lowercase() != *key);
+        }
+
+        routes.forwarders.retain(|f| {
+            !self
+                .unforwarded
+                .iter()
+                .any(|a| f.actions().iter().any(|f| f.eq_ignore_ascii_case(a)))
+        });
+
+        for (key, (id, listener)) in self.handlers.iter() {
+            routes.handlers.insert(key.clone(), listener.clone());
+            routes.methods.insert(id.clone());
+        }
+
+        routes.forwarders.extend(self.forwarders.iter().cloned());
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_listener<C: Command, L: IntoRawListener<C>>(&self, _: C, listener: L) -> &Self {
+        let listener = listener.into_listener();
+        let id = C::id();
+
+        self.change(
+            |inner| {
+                inner.removed.remove(&id.to_lowercase());
+                inner
+                    .handlers
+                    .insert(id.to_lowercase(), (id.to_string(), listener.clone()));
+            },
+            |routes| {
+                routes.handlers.insert(id.to_lowercase(), listener.clone());
+                routes.methods.insert(id.to_string());
+            },
+        );
+
+        self
+    }
+
+    ///
+    /// Remove a listener by its method (case-insensitive), including
+    /// ones registered on the original [`HandlerBuilder`](crate::HandlerBuilder).
+    ///
+    pub fn remove_listener(&self, method: &str) -> &Self {
+        let key = method.to_lowercase();
+
+        self.change(
+            |inner| {
+                inner.handlers.remove(&key);
+                inner.removed.insert(key.clone());
+            },
+            |routes| {
+                routes.handlers.remove(&key);
+                routes.methods.retain(|m| m.to_lowercase() != key);
+            },
+        );
+
+        self
+    }
+
+    ///
+    /// Add a forwarder, shared between every session.
+    ///
+    pub fn forward(&self, forwarder_in: ForwarderIn) -> &Self {
+        let forwarder = Arc::new(forwarder_in);
+
+        self.change(
+            |inner| {
+                inner
+                    .unforwarded
+                    .retain(|a| !forwarder.actions().contains(a));
+                inner.forwarders.push(forwarder.clone());
+            },
+            |routes| {
+                routes.forwarders.push(forwarder.clone());
+            },
+        );
+
+        self
+    }
+
+    ///
+    /// Remove every forwarder listening for `action`, including
+    /// ones added on the original [`HandlerBuilder`](crate::HandlerBuilder).
+    ///
+    pub fn unforward(&self, action: &str) -> &Self {
+        let listens =
+            |f: &Arc<ForwarderIn>| f.actions().iter().any(|a| a.eq_ignore_ascii_case(action));
+
+        self.change(
+            |inner| {
+                inner.forwarders.retain(|f| !listens(f));
+                inner.unforwarded.push(action.to_string());
+            },
+            |routes| {
+                routes.forwarders.retain(|f| !listens(f));
+            },
+        );
+
+        self
+    }
+
+    ///
+    /// Push a notification to every live session whenever
+    /// the handler set changes.
+    ///
+    /// `notification` is given that session's supported methods
+    /// (see [`Handler::supported_methods`](crate::Handler::supported_methods)).
+    ///
+    pub fn notify_with(
+        &self,
+        notification: impl Fn(Vec<String>) -> Response + Send + Sync + 'static,
+    ) -> &Self {
+        self.inner.lock().unwrap().notification = Some(Arc::new(notification));
+        self
+    }
+
+    ///
+    /// Number of live sessions attached.
+    ///
+    pub fn sessions(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sessions.retain(|(s, _)| s.strong_count() > 0);
+        inner.sessions.len()
+    }
+
+    ///
+    /// Apply everything registered (and removed) so far to `routes`.
+    ///
+    pub(crate) fn apply(&self, routes: &mut Routes) {
+        self.inner.lock().unwrap().apply(routes);
+    }
+
+    ///
+    /// Apply everything so far to a new session, and keep it
+    /// up to date from then on (atomically, so no change is missed).
+    ///
+    pub(crate) fn attach(&self, mut routes: Routes, tx: &Sender<Response>) -> Arc<RwLock<Routes>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.apply(&mut routes);
+
+        let routes = Arc::new(RwLock::new(routes));
+        inner.sessions.push((Arc::downgrade(&routes), tx.clone()));
+        routes
+    }
+
+    fn change(&self, registry: impl FnOnce(&mut Inner), session: impl Fn(&mut Routes)) {
+        let mut inner = self.inner.lock().unwrap();
+        registry(&mut inner);
+
+        inner.sessions.retain(|(s, _)| s.strong_count() > 0);
+
+        for (routes, tx) in inner.sessions.iter() {
+            let Some(routes) = routes.upgrade() else {
+                continue;
+            };
+
+            let methods = {
+                let mut routes = routes.write().unwrap();
+                session(&mut routes);
+                routes.supported_methods()
+            };
+
+            if let Some(ref notification) = inner.notification {
+                dispatch_event(tx, notification(methods));
+            }
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//!
+//! Serving a recorded session back, for reproducing
+//! a user's session offline (see [`record`](crate::record)).
+//!
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+
+use crate::{
+    jsonrpc::{self, Request, Response},
+    meta::{BrowserVersion, Target},
+    record::{self, Direction, Entry},
+    server::DevToolsServer,
+    util::HandlerBuilder,
+};
+
+///
+/// How closely a request has to match a recorded one.
+///
+#[derive(Debug, Clone, Default)]
+pub enum Matching {
+    ///
+    /// Same method and params.
+    ///
+    #[default]
+    Exact,
+
+    ///
+    /// Same method, any params.
+    ///
+    Method,
+
+    ///
+    /// Same method and params, apart from these (top-level) keys.
+    ///
+    IgnoreKeys(Vec<String>),
+}
+
+impl Matching {
+    fn matches(&self, recorded: &serde_json::Value, incoming: &serde_json::Value) -> bool {
+        match self {
+            Matching::Exact => recorded == incoming,
+            Matching::Method => true,
+            Matching::IgnoreKeys(keys) => {
+                let strip = |v: &serde_json::Value| {
+                    let mut v = v.clone();
+                    if let Some(obj) = v.as_object_mut() {
+                        obj.retain(|k, _| !keys.contains(k));
+                    }
+                    v
+                };
+
+                strip(recorded) == strip(incoming)
+            }
+        }
+    }
+}
+
+///
+/// A request, and what we replied with.
+///
+struct Exchange {
+    params: serde_json::Value,
+    reply: Response,
+}
+
+struct Recording {
+    target: String,
+
+    ///
+    /// Lowercase method -> its exchanges, in order.
+    ///
+    exchanges: HashMap<String, (String, Vec<Exchange>)>,
+
+    ///
+    /// Events, and when they were sent (since the session started).
+    ///
+    events: Vec<(Duration, Response)>,
+}
+
+#[derive(Clone)]
+pub struct Replay {
+    recording: Arc<Recording>,
+    matching: Matching,
+}
+
+impl Replay {
+    ///
+    /// Replay the first session in a recording.
+    ///
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_entries(record::read(path)?, None)
+    }
+
+    ///
+    /// Replay a particular session in a recording.
+    ///
+    pub fn load_session(path: impl AsRef<Path>, session: u64) -> anyhow::Result<Self> {
+        Self::from_entries(record::read(path)?, Some(session))
+    }
+
+    pub fn from_entries(
+        entries: impl IntoIterator<Item = Entry>,
+        session: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let mut entries = entries.into_iter().peekable();
+        let session = session
+            .or_else(|| entries.peek().map(|e| e.session))
+            .ok_or_else(|| anyhow!("Recording is empty"))?;
+
+        let entries = entries.filter(|e| e.session == session).collect::<Vec<_>>();
+        let start = entries
+            .first()
+            .ok_or_else(|| anyhow!("No session {session} in recording"))?
+            .timestamp;
+
+        let mut requests = HashMap::new();
+        let mut recording = Recording {
+            target: entries[0].target.clone(),
+            exchanges: HashMap::new(),
+            events: vec![],
+        };
+
+        for entry in entries {
+            match entry.direction {
+                Direction::Request => {
+                    let Ok(req) = serde_json::from_value::<Request>(entry.message) else {
+                        continue;
+                    };
+
+                    if let Some(ref id) = req.id {
+                        requests.insert(id.to_string(), req);
+                    }
+                }
+                Direction::Reply => {
+                    let Ok(reply) = serde_json::from_value::<Response>(entry.message) else {
+                        continue;
+                    };
+
+                    let Some(req) = reply
+                        .id
+                        .as_ref()
+                        .and_then(|id| requests.remove(&id.to_string()))
+                    else {
+                        continue;
+                    };
+
+                    recording
+                        .exchanges
+                        .entry(req.method.to_lowercase())
+                        .or_insert_with(|| (req.method.clone(), vec![]))
+                        .1
+                        .push(Exchange {
+                            params: req.params,
+                            reply,
+                        });
+                }
+                Direction::Event => {
+                    if let Ok(event) = serde_json::from_value(entry.message) {
+                        let at = Duration::from_millis(entry.timestamp.saturating_sub(start));
+                        recording.events.push((at, event));
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            recording: Arc::new(recording),
+            matching: Matching::default(),
+        })
+    }
+
+    pub fn matching(mut self, matching: Matching) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    ///
+    /// The id of the target that was recorded.
+    ///
+    pub fn target(&self) -> &str {
+        &self.recording.target
+    }
+
+    ///
+    /// Handlers for a single session: answering with recorded replies,
+    /// and re-sending recorded events at their original times.
+    ///
+    /// Each recorded reply is used once, earliest matching first, before
+    /// falling back to the last one that matched.
+    ///
+    pub fn handler_builder(&self) -> HandlerBuilder {
+        let mut builder = HandlerBuilder::default();
+
+        // Which replies have been used up so far, per method.
+        let used: Arc<Mutex<HashMap<String, Vec<bool>>>> = Default::default();
+
+        for (key, (method, _)) in self.recording.exchanges.iter() {
+            let recording = self.recording.clone();
+            let matching = self.matching.clone();
+            let used = used.clone();
+            let key = key.clone();
+
+            builder.add_raw_listener(
+                method,
+                Arc::new(move |req, _| {
+                    let (_, exchanges) = &recording.exchanges[&key];
+                    let mut used = used.lock().unwrap();
+                    let used = used
+                        .entry(key.clone())
+                        .or_insert_with(|| vec![false; exchanges.len()]);
+
+                    let matches =
+                        |(_, e): &(usize, &Exchange)| matching.matches(&e.params, &req.params);
+                    let matched = exchanges
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| !used[*i])
+                        .find(matches)
+                        .or_else(|| exchanges.iter().enumerate().rev().find(matches));
+
+                    match matched {
+                        Some((i, exchange)) => {
+                            used[i] = true;
+
+                            Response {
+                                id: req.id,
+                                ..exchange.reply.clone()
+                            }
+                        }
+                        None => jsonrpc::invalid_request(req.id, req.method),
+                    }
+                }),
+            );
+        }
+
+        let recording = self.recording.clone();
+        builder.on_start(move |tx| {
+            tokio::spawn(async move {
+                let start = tokio::time::Instant::now();
+
+                for (at, event) in recording.events.iter() {
+                    tokio::time::sleep_until(start + *at).await;
+
+                    if tx.send(event.clone()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        });
+
+        builder
+    }
+
+    ///
+    /// A server, on `port`, serving just the recorded target.
+    ///
+    pub fn server(self, port: u16) -> DevToolsServer {
+        let id = self.recording.target.clone();
+        let target = Target {
+            id: id.clone(),
+            title: format!("Replay of {id}"),
+            description: "A recorded session, replayed.".to_string(),
+            devtools_frontend_url: format!(
+                "/devtools/inspector.html?ws=localhost:{port}/devtools/page/{id}"
+            )
+            .into(),
+            web_socket_debugger_url: format!("ws://localhost:{port}/devtools/page/{id}"),
+            ..Target::default()
+        };
+
+        DevToolsServer::new(
+            BrowserVersion::default(),
+            vec![target],
+            port,
+            Box::new(move || self.handler_builder()),
+            None,
+        )
+    }
+}
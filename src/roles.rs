@@ -0,0 +1,62 @@
+//!
+//! What a session is allowed to call, depending on who opened it
+//! (see [`Auth::role`](crate::Auth::role)).
+//!
+
+use crate::util::action_matches;
+
+///
+/// A named set of allowed (and denied) methods/domains,
+/// in the same format as [`Fallback::Acknowledge`](crate::Fallback::Acknowledge).
+///
+/// e.g. for only observing:
+/// ```ignore
+/// Role::new("observer")
+///     .allow("Runtime.enable")
+///     .allow("Log.")
+///     .allow("Performance.")
+///     .deny("Runtime.evaluate")
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct Role {
+    name: String,
+
+    ///
+    /// If empty, anything (not denied) is allowed.
+    ///
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl Role {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+
+    pub fn allow(mut self, action: impl Into<String>) -> Self {
+        self.allow.push(action.into());
+        self
+    }
+
+    pub fn deny(mut self, action: impl Into<String>) -> Self {
+        self.deny.push(action.into());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    ///
+    /// Whether sessions with this role may call `method`.
+    ///
+    pub fn allows(&self, method: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|a| action_matches(a, method)))
+            && !self.deny.iter().any(|a| action_matches(a, method))
+    }
+}
@@ -1,42 +1,55 @@
-use futures_util::StreamExt;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use futures_util::SinkExt;
 use serde_json::json;
-use warp::{
-    filters::ws::{self, WebSocket},
-    http::StatusCode,
-    Filter,
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
 };
+use tracing::Instrument;
+use warp::{filters::ws, host::Authority, http::StatusCode, Filter, Rejection, Reply};
 
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
 use crate::{
+    allow::{self, Allowlist},
+    auth::{self, Auth},
+    jsonrpc::Response,
     meta::{self, MetaOperation},
-    traffic::{arriving, departing},
+    record::Recorder,
+    redact::Redactor,
+    tls::{self, Certificates, Pem, TLS},
+    traffic::{
+        arriving, departing, AttachPolicy, Channels, Keepalive, Limits, SessionInfo, SessionOptions,
+    },
+    transport::{Frame, Pipe, Transport},
     util::{self, HandlerBuilder},
 };
 
-#[derive(Debug, Clone)]
-pub struct TLS {
-    pub(crate) port: u16,
-    pub(crate) certificate: &'static str,
-    pub(crate) private_key: &'static str,
-}
-
-impl TLS {
-    pub fn new(port: u16, certificate_path: &'static str, private_key_path: &'static str) -> Self {
-        Self {
-            port,
-            certificate: certificate_path,
-            private_key: private_key_path,
-        }
-    }
-}
-
 ///
 /// Helper struct to encompass all neccessary
 /// info to be picked up by chrome.
 ///
 pub struct DevToolsServer {
+    ///
+    /// Addresses to listen on, for both HTTP and HTTPS.
+    ///
+    bind: Vec<IpAddr>,
     port: u16,
+
+    ///
+    /// Whether to serve plain HTTP (on `port`) at all.
+    ///
+    http: bool,
     tls: Option<TLS>,
-    handler_builder: Box<dyn Fn() -> HandlerBuilder + Send + Sync>,
+    handler_builder: Arc<dyn Fn() -> HandlerBuilder + Send + Sync>,
+    session: SessionOptions,
+    allow: Allowlist,
+    auth: Auth,
     pub(crate) version: meta::BrowserVersion,
     pub(crate) targets: Vec<meta::Target>,
 }
@@ -50,94 +63,593 @@ impl DevToolsServer {
         tls: impl Into<Option<TLS>>,
     ) -> Self {
         Self {
+            bind: vec![Ipv4Addr::LOCALHOST.into()],
             port,
+            http: true,
             version,
             targets,
-            handler_builder,
+            handler_builder: handler_builder.into(),
+            session: SessionOptions::default(),
+            allow: Allowlist::default(),
+            auth: Auth::default(),
             tls: tls.into(),
         }
     }
 
-    pub async fn handle_client(websocket: WebSocket, handler: util::HandlerBuilder) {
-        // Set up channels for this socket.
-        // Mainly for my sanity.
-        let (raw_tx, raw_rx) = websocket.split();
-        let [reply_tx, err_tx, events_tx] = departing(raw_tx);
-        let mut rx = arriving(raw_rx, err_tx);
+    ///
+    /// Listen on these addresses (IPv4 and/or IPv6), instead of just `127.0.0.1`.
+    ///
+    /// With port 0, each address gets its own ephemeral port
+    /// (see [`ServerHandle::http_addrs`]).
+    ///
+    pub fn with_bind(mut self, addrs: impl IntoIterator<Item = impl Into<IpAddr>>) -> Self {
+        self.bind = addrs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    ///
+    /// Only serve HTTPS, leaving `port` alone.
+    ///
+    /// Implied by [`TLS::with_client_ca`], as plain HTTP would skip the certificate check.
+    ///
+    pub fn without_http(mut self) -> Self {
+        self.http = false;
+        self
+    }
+
+    ///
+    /// Which `Origin`s and `Host`s may use `/json` and open sessions.
+    ///
+    pub fn with_allowlist(mut self, allow: Allowlist) -> Self {
+        self.allow = allow;
+        self
+    }
+
+    ///
+    /// Require tokens to open sessions (see [`Auth`]).
+    ///
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    ///
+    /// Set the channel capacities (and backpressure policy) for each session.
+    ///
+    pub fn with_channels(mut self, channels: Channels) -> Self {
+        self.session.channels = channels;
+        self
+    }
+
+    ///
+    /// Limit how much each client can send, and how many can attach.
+    ///
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.session.limits = limits;
+        self
+    }
+
+    ///
+    /// How `target` handles more than one client
+    /// (e.g. for backends that can only serve one session).
+    ///
+    pub fn with_attach_policy(self, target: impl Into<String>, policy: AttachPolicy) -> Self {
+        self.session.attached.set_policy(target, policy);
+        self
+    }
+
+    ///
+    /// Set (or turn off, with `None`) pinging clients
+    /// and closing idle sessions.
+    ///
+    pub fn with_keepalive(mut self, keepalive: impl Into<Option<Keepalive>>) -> Self {
+        self.session.keepalive = keepalive.into();
+        self
+    }
+
+    ///
+    /// Record every session's traffic.
+    ///
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.session.recorder = Some(recorder);
+        self
+    }
+
+    ///
+    /// Filter and redact what goes into traffic logs.
+    ///
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.session.redactor = Some(redactor);
+        self
+    }
+
+    ///
+    /// Count sessions, requests and events into `metrics`,
+    /// and serve them at `/metrics`.
+    ///
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.session.metrics = Some(metrics);
+        self
+    }
+
+    ///
+    /// Serve a single session over any [`Transport`],
+    /// until the client goes away.
+    ///
+    pub async fn handle_client(
+        mut transport: impl Transport,
+        handler: util::HandlerBuilder,
+        mut options: SessionOptions,
+        info: SessionInfo,
+    ) {
+        let span = tracing::info_span!("session", id = info.id, target = %info.target);
+
+        async move {
+            // Set up channels for this socket.
+            // Mainly for my sanity.
+            transport.max_message(options.limits.max_message);
+            if !transport.supports_ping() {
+                // A quiet client isn't a dead one if it can't answer pings.
+                options.keepalive = None;
+            }
+            let (mut raw_tx, raw_rx) = transport.split();
+
+            let limit = options.limits.sessions_per_target;
+            let Some(mut attached) = options.attached.attach(&info, limit) else {
+                let policy = options.attached.policy(&info.target);
+                tracing::warn!(limit, ?policy, "Too many sessions for target, closing");
+
+                let reason = "Too many sessions for this target".to_string();
+                let _ = raw_tx.send(Frame::Close(Some((1008, reason)))).await;
+                let _ = raw_tx.close().await;
+                return;
+            };
+
+            tracing::info!("Session started");
+
+            // Dropped when the session ends, so departing() knows to finish up.
+            let (_done, done) = oneshot::channel::<()>();
+            let reply_tx = departing(raw_tx, &options, &info, async {
+                let _ = done.await;
+            });
+            let mut rx = arriving(raw_rx, reply_tx.clone(), &options, &info);
+
+            let handler = handler.build(reply_tx.clone());
+            attached.answers(handler.routes());
+
+            #[cfg(feature = "metrics")]
+            if let Some(ref metrics) = options.metrics {
+                metrics.session_started(&info.target, handler.routes());
+            }
+
+            let replaced = loop {
+                let req = tokio::select! {
+                    req = rx.recv() => match req {
+                        Some(req) => req,
+                        None => break false,
+                    },
+                    _ = attached.replaced() => break true,
+                };
+
+                #[cfg(feature = "metrics")]
+                let (method, start) =
+                    (handler.supported_as(&req.method), std::time::Instant::now());
+
+                // Don't keep waiting on (say) a forwarder for a session that's been replaced.
+                let res = tokio::select! {
+                    res = handler.handle_incoming(req) => res,
+                    _ = attached.replaced() => break true,
+                };
 
-        let handler = handler.build(events_tx);
+                #[cfg(feature = "metrics")]
+                if let Some(ref metrics) = options.metrics {
+                    let error = res.error.as_ref().map(|e| e.code);
+                    metrics.request(method.as_deref(), start.elapsed(), error);
+                }
 
-        while let Some(req) = rx.recv().await {
-            let res = handler.handle_incoming(req);
-            reply_tx.send(res.await).await.expect("Could not send reply");
+                if reply_tx.send(res).await.is_err() {
+                    // Nothing's going out any more, so the client's as good as gone.
+                    break false;
+                }
+            };
+
+            if replaced {
+                tracing::info!("Replaced by a newer session, detaching");
+
+                let detached = Response {
+                    method: Some("Inspector.detached".to_string()),
+                    params: Some(json!({ "reason": "replaced_with_devtools" })),
+                    result: None,
+                    id: None,
+                    ..Default::default()
+                };
+                let _ = reply_tx.send(detached).await;
+            }
+
+            #[cfg(feature = "metrics")]
+            if let Some(ref metrics) = options.metrics {
+                metrics.session_ended(&info.target);
+            }
+
+            tracing::info!("Session ended");
         }
+        .instrument(span)
+        .await
+    }
+
+    ///
+    /// A fresh session's handlers, reporting our protocol version.
+    ///
+    fn handler_builder(&self) -> HandlerBuilder {
+        let mut handler = (self.handler_builder)();
+        handler.protocol_version(&self.version.protocol_version);
+        handler
+    }
+
+    ///
+    /// Serve a single session (to `target`) over `transport`,
+    /// using this server's handlers and session options.
+    ///
+    pub async fn serve(&self, transport: impl Transport, target: impl Into<String>) {
+        Self::handle_client(
+            transport,
+            self.handler_builder(),
+            self.session.clone(),
+            SessionInfo::new(target),
+        )
+        .await
     }
 
+    ///
+    /// Serve a single session over Chrome's `--remote-debugging-pipe`
+    /// protocol (reading from fd 3, writing to fd 4), instead of opening any ports.
+    ///
+    /// # Safety
+    /// See [`Pipe::from_fds`].
+    ///
+    #[cfg(unix)]
+    pub async unsafe fn run_pipe(self) -> anyhow::Result<()> {
+        let pipe = Pipe::from_fds();
+        let target = self
+            .targets
+            .first()
+            .map(|t| t.id.clone())
+            .unwrap_or_default();
+
+        Self::handle_client(
+            pipe,
+            self.handler_builder(),
+            self.session,
+            SessionInfo::new(target),
+        )
+        .await;
+
+        Ok(())
+    }
+
+    ///
+    /// Serve until the server is shut down.
+    ///
     pub async fn run(self) -> anyhow::Result<()> {
-        let builder = Box::leak(self.handler_builder);
+        self.start().await?.wait().await;
+        Ok(())
+    }
+
+    ///
+    /// Every route (`/json*`, `/devtools/page/*` and, with metrics, `/metrics`),
+    /// under `prefix` (e.g. `"debug"` or `"tools/debug"`), for mounting into
+    /// an existing warp server with [`Filter::or`].
+    ///
+    /// Anything not matching them is rejected as usual, so other routes still get a go.
+    /// Sessions are only opened for our own targets: other page ids get a 404.
+    /// Targets' URLs are listed, like Chrome's, with the host the client asked for
+    /// and under the prefix.
+    ///
+    pub fn routes(
+        &self,
+        prefix: Option<&str>,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static
+    {
+        let segments: Vec<&str> = prefix
+            .unwrap_or_default()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        // Where sessions are opened, for the URLs we list.
+        let pages: Arc<str> = segments
+            .iter()
+            .map(|segment| format!("/{segment}"))
+            .chain(["/devtools/page".to_string()])
+            .collect::<String>()
+            .into();
+
+        let prefix = segments
+            .into_iter()
+            .fold(warp::any().boxed(), |prefix, segment| {
+                prefix.and(warp::path(segment.to_string())).boxed()
+            });
+
+        // Checked once a route matches, so we don't turn away requests meant for others.
+        let allowed = allow::check(Arc::new(self.allow.clone()));
+
+        let session = self.session.clone();
+
+        #[cfg(feature = "metrics")]
+        let metrics = session.metrics.clone();
+        let attached = session.attached.clone();
+
+        let handler_builder = self.handler_builder.clone();
+        let protocol_version = self.version.protocol_version.clone();
+        let known: HashSet<String> = self.targets.iter().map(|t| t.id.clone()).collect();
+        let auth = self.auth.clone();
         let sockets = warp::path!("devtools" / "page" / String)
+            .and(allowed.clone())
+            .and(auth::token())
             .and(warp::ws())
-            .map(|page_id: String, ws: ws::Ws| {
-                println!("Web socket for page {}", page_id.as_str());
+            .map(move |page_id: String, token: Option<String>, ws: ws::Ws| {
+                // Only targets we list, so made-up ids can't dodge their tokens or policies.
+                if !known.contains(&page_id) {
+                    tracing::warn!(page = %page_id, "Rejected session for unknown target");
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+
+                if !auth.allows(&page_id, token.as_deref()) {
+                    tracing::warn!(page = %page_id, "Rejected session without a valid token");
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+
+                tracing::debug!(page = %page_id, "WebSocket upgrade");
                 // And then our closure will be called when it completes...
-                ws.on_upgrade(|w| Self::handle_client(w, builder()))
+                let mut handler = handler_builder();
+                handler.protocol_version(&protocol_version);
+                if let Some(role) = auth.role_of(token.as_deref()) {
+                    handler.role(role);
+                }
+                let session = session.clone();
+                let max = session.limits.max_message;
+
+                // Have the WebSocket itself refuse anything far too big to bother reading.
+                ws.max_message_size(max.saturating_mul(2))
+                    .on_upgrade(move |w| {
+                        Self::handle_client(w, handler, session, SessionInfo::new(page_id))
+                    })
+                    .into_response()
             });
 
         let version = self.version.clone();
         let targets = self.targets.clone();
-            
-
-        let meta = warp::path!("json" / String).map(move |operation: String| {
-            TryInto::<MetaOperation>::try_into(operation)
-                .map(|op| {
-                    warp::reply::with_status(
-                        warp::reply::json(&op.exec(&version, &targets)),
-                        StatusCode::OK,
-                    )
-                })
-                .unwrap_or_else(|()| {
-                    warp::reply::with_status(warp::reply::json(&json!({})), StatusCode::NOT_FOUND)
-                })
-        });
-
-        
-
-        let meta_route = warp::path!("json").map(move || {
-            warp::reply::with_status(
-                warp::reply::json(&MetaOperation::Targets.exec(&self.version, &self.targets)),
-                StatusCode::OK,
-            )
-        });
+
+        let auth = self.auth.clone();
+        let listed = attached.clone();
+        let at = pages.clone();
+        let meta = warp::path!("json" / String)
+            .and(allowed.clone())
+            .and(auth::token())
+            .and(warp::host::optional())
+            .map(move |operation: String, token: Option<String>, host| {
+                TryInto::<MetaOperation>::try_into(operation)
+                    .map(|op| {
+                        let targets = served_at(&targets, &host, &at);
+                        let mut targets = auth.embed(&targets, token.as_deref());
+                        listed.mark(&mut targets);
+                        // From live sessions, so we never start one just to ask.
+                        let methods = match op {
+                            MetaOperation::Protocol => listed.supported_methods(),
+                            _ => vec![],
+                        };
+                        warp::reply::with_status(
+                            warp::reply::json(&op.exec(&version, &targets, &methods)),
+                            StatusCode::OK,
+                        )
+                    })
+                    .unwrap_or_else(|()| {
+                        warp::reply::with_status(
+                            warp::reply::json(&json!({})),
+                            StatusCode::NOT_FOUND,
+                        )
+                    })
+            });
+
+        let (version, targets) = (self.version.clone(), self.targets.clone());
+        let auth = self.auth.clone();
+        let meta_route = warp::path!("json")
+            .and(allowed.clone())
+            .and(auth::token())
+            .and(warp::host::optional())
+            .map(move |token: Option<String>, host| {
+                let targets = served_at(&targets, &host, &pages);
+                let mut targets = auth.embed(&targets, token.as_deref());
+                attached.mark(&mut targets);
+                warp::reply::with_status(
+                    warp::reply::json(&MetaOperation::Targets.exec(&version, &targets, &[])),
+                    StatusCode::OK,
+                )
+            });
 
         let routes = sockets.or(meta).or(meta_route);
 
-        println!("DEBUG > HTTP Running on *:{}", self.port);
+        #[cfg(feature = "metrics")]
+        let routes = routes.or(warp::path!("metrics").and(allowed).and_then(move || {
+            let metrics = metrics.clone();
+            async move {
+                match metrics {
+                    Some(metrics) => Ok(warp::reply::with_header(
+                        metrics.render(),
+                        "Content-Type",
+                        "text/plain; version=0.0.4",
+                    )),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        }));
+
+        prefix.and(routes).recover(allow::forbidden)
+    }
+
+    ///
+    /// Bind every address, and serve in the background.
+    ///
+    pub async fn start(self) -> anyhow::Result<ServerHandle> {
+        anyhow::ensure!(!self.bind.is_empty(), "No addresses to bind to");
+
+        let mtls = self.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some());
+        let http = self.http && !mtls;
+        anyhow::ensure!(
+            http || self.tls.is_some(),
+            "Neither HTTP nor HTTPS to serve"
+        );
+
+        let routes = self.routes(None);
+
+        let (shutdown, signal) = watch::channel(false);
+        let signal = move || {
+            let mut signal = signal.clone();
+            async move {
+                let _ = signal.wait_for(|stop| *stop).await;
+            }
+        };
+
+        let mut handle = ServerHandle {
+            auth: self.auth,
+            http: vec![],
+            https: vec![],
+            shutdown,
+            servers: vec![],
+            certificates: None,
+        };
 
-        let http = warp::serve(routes.clone()).run(([0, 0, 0, 0], self.port));
+        if mtls && self.http {
+            tracing::info!("Not serving HTTP, as HTTPS requires client certificates");
+        }
+
+        for ip in self.bind.iter().filter(|_| http) {
+            let (addr, server) = warp::serve(routes.clone())
+                .try_bind_with_graceful_shutdown((*ip, self.port), signal())?;
+
+            tracing::info!("HTTP running on {addr}");
+            handle.http.push(addr);
+            handle.servers.push(tokio::spawn(server));
+        }
 
         if let Some(ref tls) = self.tls {
-            println!("DEBUG > HTTPS Running on *:{}", tls.port);
+            let (acceptor, certificates) = tls.acceptor()?;
 
-            let https = warp::serve(routes)
-                .tls()
-                .cert_path(tls.certificate)
-                .key_path(tls.private_key)
-                .run(([0, 0, 0, 0], tls.port));
+            for ip in self.bind.iter() {
+                let (addr, incoming) = tls::bind((*ip, tls.port).into(), acceptor.clone()).await?;
+                let server = warp::serve(routes.clone())
+                    .serve_incoming_with_graceful_shutdown(incoming, signal());
 
-            futures::future::join(http, https).await;
-        } else {
-            http.await;
+                tracing::info!("HTTPS running on {addr}");
+                handle.https.push(addr);
+                handle.servers.push(tokio::spawn(server));
+            }
+
+            if let Some(interval) = tls.watch {
+                tokio::spawn(certificates.clone().watch(interval, signal()));
+            }
+
+            handle.certificates = Some(certificates);
         }
 
-        let builder = unsafe {
-            Box::from_raw_in(
-                builder as *const (dyn Fn() -> HandlerBuilder + Send + Sync)
-                    as *mut (dyn Fn() -> HandlerBuilder + Send + Sync),
-                std::alloc::Global,
-            )
-        };
-        drop(builder);
+        Ok(handle)
+    }
+}
 
-        Ok(())
+///
+/// `targets` as listed to a client that reached us at `host`,
+/// or as given if it didn't say.
+///
+fn served_at(targets: &[meta::Target], host: &Option<Authority>, pages: &str) -> Vec<meta::Target> {
+    targets
+        .iter()
+        .cloned()
+        .map(|target| match host {
+            Some(host) => target.served_at(host.as_str(), pages),
+            None => target,
+        })
+        .collect()
+}
+
+///
+/// A running [`DevToolsServer`].
+///
+pub struct ServerHandle {
+    auth: Auth,
+    http: Vec<SocketAddr>,
+    https: Vec<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    servers: Vec<JoinHandle<()>>,
+
+    ///
+    /// What HTTPS is being served with, if at all.
+    ///
+    certificates: Option<Certificates>,
+}
+
+impl ServerHandle {
+    ///
+    /// The server's tokens, for rotating them.
+    ///
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    ///
+    /// Where HTTP is actually being served, if at all
+    /// (with the real port, if bound to port 0).
+    ///
+    pub fn http_addrs(&self) -> &[SocketAddr] {
+        &self.http
+    }
+
+    ///
+    /// Where HTTPS is actually being served, if at all.
+    ///
+    pub fn https_addrs(&self) -> &[SocketAddr] {
+        &self.https
+    }
+
+    ///
+    /// Read the TLS certificate and key files again, and use them
+    /// for new connections (open sessions carry on as they are).
+    ///
+    pub fn reload_tls(&self) -> anyhow::Result<()> {
+        self.certificates()?.reload()
+    }
+
+    ///
+    /// Serve a different TLS certificate and key to new connections
+    /// (open sessions carry on as they are).
+    ///
+    pub fn replace_tls(
+        &self,
+        certificate: impl Into<Pem>,
+        private_key: impl Into<Pem>,
+    ) -> anyhow::Result<()> {
+        self.certificates()?
+            .replace(certificate.into(), private_key.into())
+    }
+
+    fn certificates(&self) -> anyhow::Result<&Certificates> {
+        self.certificates
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not serving HTTPS"))
+    }
+
+    ///
+    /// Wait until every listener has stopped.
+    ///
+    pub async fn wait(self) {
+        futures::future::join_all(self.servers).await;
+    }
+
+    ///
+    /// Stop accepting connections, and wait for open ones to finish.
+    ///
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        self.wait().await;
     }
 }
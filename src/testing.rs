@@ -0,0 +1,132 @@
+//!
+//! In-process sessions, for testing handlers without any sockets.
+//!
+
+use std::collections::VecDeque;
+
+use chrome_devtools_api::{Command, Event};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::mpsc::Receiver;
+
+use crate::{
+    jsonrpc::{Request, Response, RpcError},
+    traffic::Channels,
+    util::{Handler, HandlerBuilder},
+};
+
+///
+/// Drives a [`Handler`] directly, as a client would.
+///
+/// ```ignore
+/// let mut session = TestSession::new(builder);
+/// let document = session.send(protocol::dom::GetDocument, Default::default()).await?;
+/// ```
+///
+pub struct TestSession {
+    handler: Handler,
+    events_rx: Receiver<Response>,
+
+    ///
+    /// Events received, but not taken yet.
+    ///
+    events: VecDeque<Response>,
+    next_id: u64,
+}
+
+impl TestSession {
+    pub fn new(builder: HandlerBuilder) -> Self {
+        let (events_tx, events_rx) = tokio::sync::mpsc::channel(Channels::default().events);
+
+        Self {
+            handler: builder.build(events_tx),
+            events_rx,
+            events: VecDeque::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn handler(&self) -> &Handler {
+        &self.handler
+    }
+
+    ///
+    /// Send a typed command, and wait for its (typed) reply.
+    ///
+    pub async fn send<C>(&mut self, _: C, params: C::Parameters) -> Result<C::Returns, RpcError>
+    where
+        C: Command,
+        C::Parameters: Serialize,
+        C::Returns: DeserializeOwned,
+    {
+        let params = serde_json::to_value(params).map_err(RpcError::internal_error)?;
+        let res = self.send_raw(&C::id().to_string(), params).await;
+
+        match (res.error, res.result) {
+            (Some(e), _) => Err(e),
+            (None, Some(result)) => {
+                serde_json::from_value(result).map_err(RpcError::internal_error)
+            }
+            (None, None) => Err(RpcError::internal_error("Reply had no result")),
+        }
+    }
+
+    ///
+    /// Send any method, and wait for the raw reply.
+    ///
+    pub async fn send_raw(&mut self, method: &str, params: serde_json::Value) -> Response {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.handler
+            .handle_incoming(Request {
+                jsonrpc: "2.0".to_string(),
+                method: method.to_string(),
+                params,
+                id: Some(id.into()),
+            })
+            .await
+    }
+
+    ///
+    /// Wait for the next event.
+    ///
+    pub async fn next_event(&mut self) -> Option<Response> {
+        match self.events.pop_front() {
+            Some(event) => Some(event),
+            None => self.events_rx.recv().await,
+        }
+    }
+
+    ///
+    /// Every event emitted so far.
+    ///
+    pub fn events(&mut self) -> Vec<Response> {
+        self.collect();
+        self.events.drain(..).collect()
+    }
+
+    ///
+    /// Every `E` emitted so far, leaving other events be.
+    ///
+    pub fn events_of<E: Event + DeserializeOwned>(&mut self) -> Vec<E> {
+        self.collect();
+
+        let id = E::__id().to_string();
+        let (matching, rest) = self
+            .events
+            .drain(..)
+            .partition::<VecDeque<_>, _>(|e| e.method.as_deref() == Some(id.as_str()));
+        self.events = rest;
+
+        matching
+            .into_iter()
+            .filter_map(|e| serde_json::from_value(e.params?).ok())
+            .collect()
+    }
+
+    fn collect(&mut self) {
+        while let Ok(event) = self.events_rx.try_recv() {
+            self.events.push_back(event);
+        }
+    }
+}
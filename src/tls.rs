@@ -0,0 +1,354 @@
+//!
+//! Serving HTTPS, with certificates that can be swapped out
+//! (for new connections) while the server is running.
+//!
+
+use std::{
+    fmt, fs,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use futures::{Future, Stream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring::sign::any_supported_type,
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::certs::SelfSigned;
+
+///
+/// A certificate, key or CA bundle, in PEM format.
+///
+#[derive(Clone)]
+pub enum Pem {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl Pem {
+    pub fn path(path: impl Into<PathBuf>) -> Self {
+        Self::Path(path.into())
+    }
+
+    pub fn bytes(pem: impl Into<Vec<u8>>) -> Self {
+        Self::Bytes(pem.into())
+    }
+
+    fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Pem::Path(path) => fs::read(path),
+            Pem::Bytes(pem) => Ok(pem.clone()),
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Pem::Path(path) => fs::metadata(path).and_then(|m| m.modified()).ok(),
+            Pem::Bytes(_) => None,
+        }
+    }
+}
+
+impl From<&str> for Pem {
+    fn from(path: &str) -> Self {
+        Self::path(path)
+    }
+}
+
+impl From<String> for Pem {
+    fn from(path: String) -> Self {
+        Self::path(path)
+    }
+}
+
+impl From<PathBuf> for Pem {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<Vec<u8>> for Pem {
+    fn from(pem: Vec<u8>) -> Self {
+        Self::Bytes(pem)
+    }
+}
+
+// Keep private keys out of logs.
+impl fmt::Debug for Pem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pem::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            Pem::Bytes(pem) => write!(f, "Bytes(<{} bytes>)", pem.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TLS {
+    pub(crate) port: u16,
+    pub(crate) certificate: Pem,
+    pub(crate) private_key: Pem,
+
+    ///
+    /// If set, clients must present a certificate signed by one of these CAs.
+    ///
+    pub(crate) client_ca: Option<Pem>,
+
+    ///
+    /// SHA-256 fingerprint of the certificate, if we made it ourselves.
+    ///
+    pub(crate) fingerprint: Option<String>,
+
+    ///
+    /// How often to check the certificate files for changes, if at all.
+    ///
+    pub(crate) watch: Option<Duration>,
+}
+
+impl TLS {
+    ///
+    /// `certificate` and `private_key` being file paths, or [`Pem::bytes`].
+    ///
+    pub fn new(port: u16, certificate: impl Into<Pem>, private_key: impl Into<Pem>) -> Self {
+        Self {
+            port,
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+            client_ca: None,
+            fingerprint: None,
+            watch: None,
+        }
+    }
+
+    ///
+    /// Serve HTTPS on `port` with a freshly generated self-signed certificate
+    /// (see [`SelfSigned`](crate::certs::SelfSigned) for more options).
+    ///
+    pub fn self_signed(port: u16) -> anyhow::Result<Self> {
+        SelfSigned::new().generate(port)
+    }
+
+    ///
+    /// The certificate's SHA-256 fingerprint, if it's [`self_signed`](Self::self_signed).
+    ///
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    ///
+    /// Require (and verify) client certificates against the CA bundle `ca` (mTLS).
+    ///
+    /// Plain HTTP is then turned off (see
+    /// [`DevToolsServer::without_http`](crate::DevToolsServer::without_http)),
+    /// as anyone reaching it would skip the check.
+    ///
+    pub fn with_client_ca(mut self, ca: impl Into<Pem>) -> Self {
+        self.client_ca = Some(ca.into());
+        self
+    }
+
+    ///
+    /// Check the certificate and key files for changes every `interval`,
+    /// and reload them when they do.
+    ///
+    pub fn watch(mut self, interval: Duration) -> Self {
+        self.watch = Some(interval);
+        self
+    }
+
+    ///
+    /// Load everything, ready to accept connections.
+    ///
+    pub(crate) fn acceptor(&self) -> anyhow::Result<(TlsAcceptor, Certificates)> {
+        let certificates = Certificates::new(self.certificate.clone(), self.private_key.clone())?;
+
+        let builder = ServerConfig::builder();
+        let builder = match self.client_ca {
+            Some(ref ca) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) = roots.add_parsable_certificates(
+                    rustls_pemfile::certs(&mut BufReader::new(&ca.read()?[..]))
+                        .collect::<Result<Vec<_>, _>>()?,
+                );
+                anyhow::ensure!(added > 0, "No CA certificates in {ca:?}");
+
+                builder.with_client_cert_verifier(
+                    WebPkiClientVerifier::builder(Arc::new(roots)).build()?,
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_cert_resolver(Arc::new(certificates.clone()));
+        // WebSockets need HTTP/1.1.
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok((TlsAcceptor::from(Arc::new(config)), certificates))
+    }
+}
+
+///
+/// The certificate currently being served, and where it came from.
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Certificates {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    sources: Mutex<(Pem, Pem)>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    fn new(certificate: Pem, private_key: Pem) -> anyhow::Result<Self> {
+        let current = load(&certificate, &private_key)?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                sources: Mutex::new((certificate, private_key)),
+                current: RwLock::new(current),
+            }),
+        })
+    }
+
+    ///
+    /// Read the certificate and key again.
+    ///
+    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let sources = self.inner.sources.lock().unwrap();
+        *self.inner.current.write().unwrap() = load(&sources.0, &sources.1)?;
+        Ok(())
+    }
+
+    ///
+    /// Serve a different certificate and key from now on.
+    ///
+    pub(crate) fn replace(&self, certificate: Pem, private_key: Pem) -> anyhow::Result<()> {
+        let mut sources = self.inner.sources.lock().unwrap();
+        *self.inner.current.write().unwrap() = load(&certificate, &private_key)?;
+        *sources = (certificate, private_key);
+        Ok(())
+    }
+
+    ///
+    /// When the certificate and key files were last changed
+    /// (`None` for in-memory ones).
+    ///
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let sources = self.inner.sources.lock().unwrap();
+        Some((sources.0.modified()?, sources.1.modified()?))
+    }
+
+    ///
+    /// Reload whenever the files change, until `stop`.
+    ///
+    pub(crate) async fn watch(self, interval: Duration, stop: impl Future<Output = ()>) {
+        let mut last = self.modified();
+        let mut tick = tokio::time::interval(interval);
+        tokio::pin!(stop);
+
+        loop {
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = tick.tick() => {}
+            }
+
+            let modified = self.modified();
+            if modified.is_none() || modified == last {
+                continue;
+            }
+
+            // Whatever's writing them might not be done yet, so try again next time.
+            match self.reload() {
+                Ok(()) => {
+                    last = modified;
+                    tracing::info!("Reloaded TLS certificate");
+                }
+                Err(e) => tracing::warn!(error = %e, "Could not reload TLS certificate"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.inner.current.read().unwrap().clone())
+    }
+}
+
+fn load(certificate: &Pem, private_key: &Pem) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(&certificate.read()?[..]))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Could not parse certificate {certificate:?}"))?;
+    anyhow::ensure!(!certs.is_empty(), "No certificates in {certificate:?}");
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(&private_key.read()?[..]))
+        .with_context(|| format!("Could not parse private key {private_key:?}"))?
+        .ok_or_else(|| anyhow!("No private key in {private_key:?}"))?;
+
+    Ok(Arc::new(CertifiedKey::new(
+        certs,
+        any_supported_type(&key)?,
+    )))
+}
+
+///
+/// Bind `addr`, then do TLS handshakes (in the background, so slow
+/// clients don't hold anyone else up) for every connection.
+///
+pub(crate) async fn bind(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+) -> io::Result<(
+    SocketAddr,
+    impl Stream<Item = io::Result<TlsStream<TcpStream>>>,
+)> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = tokio::select! {
+                // Server's gone.
+                _ = tx.closed() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Could not accept connection");
+                        continue;
+                    }
+                },
+            };
+
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Err(e) => tracing::debug!(%peer, error = %e, "TLS handshake failed"),
+                }
+            });
+        }
+    });
+
+    Ok((
+        addr,
+        futures::stream::unfold(rx, |mut rx| async move { Some((rx.recv().await?, rx)) }),
+    ))
+}
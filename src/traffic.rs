@@ -1,84 +1,1012 @@
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc::{Receiver, Sender};
-use warp::ws::{Message, WebSocket};
 
-use crate::jsonrpc::{self, Response};
+use futures_util::{Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use tokio::sync::{
+    mpsc::{error::TrySendError, Receiver, Sender},
+    oneshot, Notify,
+};
+use tracing::Instrument;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::{
+    jsonrpc::{self, Response},
+    meta::Target,
+    record::{Direction, Recorder},
+    redact::Redactor,
+    transport::Frame,
+    util::Routes,
+};
+
+///
+/// What to do with events when a client can't keep up.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    ///
+    /// Make whoever is sending events wait.
+    ///
+    #[default]
+    Block,
+
+    ///
+    /// Drop the oldest queued event to make room.
+    ///
+    DropOldest,
+
+    ///
+    /// Drop a queued event of the same method in favour of the newer one,
+    /// dropping the oldest if there's still no room.
+    ///
+    Coalesce,
+}
+
+///
+/// Per-session channel capacities (0 is taken as 1).
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Channels {
+    ///
+    /// Incoming requests waiting to be handled.
+    ///
+    pub requests: usize,
+
+    ///
+    /// Replies and events on their way to the send queue
+    /// (i.e. the `Sender<Response>` listeners and forwarders are given).
+    ///
+    /// They're taken off straight away, unless [`Backpressure::Block`] says otherwise,
+    /// or this many replies are already waiting to be sent.
+    ///
+    pub replies: usize,
+
+    ///
+    /// Events waiting to be sent, before the [`Backpressure`] policy kicks in.
+    ///
+    pub events: usize,
+
+    pub backpressure: Backpressure,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self {
+            requests: 16,
+            replies: 16,
+            events: 16,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+///
+/// Pinging clients, and closing ones that went quiet.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    ///
+    /// How often to send a ping.
+    ///
+    pub interval: Duration,
+
+    ///
+    /// How long a client can go without sending anything
+    /// (including pongs) before we close the session.
+    ///
+    pub idle_timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+///
+/// How much a single client can send, and how many can attach.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    ///
+    /// Largest message (in bytes) a client can send.
+    ///
+    pub max_message: usize,
+
+    ///
+    /// How deeply objects/arrays can nest in a message.
+    ///
+    pub max_depth: usize,
+
+    ///
+    /// Requests a session can send per second (on average),
+    /// or `None` for no limit.
+    ///
+    pub requests_per_second: Option<u32>,
+
+    ///
+    /// Sessions that can be attached to a single target at once,
+    /// or `None` for no limit (see also [`AttachPolicy`]).
+    ///
+    pub sessions_per_target: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message: 16 << 20,
+            max_depth: 64,
+            requests_per_second: None,
+            sessions_per_target: None,
+        }
+    }
+}
+
+///
+/// What to do when a client attaches to a target that already has one.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttachPolicy {
+    ///
+    /// Let any number of clients attach (up to [`Limits::sessions_per_target`]).
+    ///
+    #[default]
+    Many,
+
+    ///
+    /// One client at a time, turning away any others while it's attached.
+    ///
+    RejectNew,
+
+    ///
+    /// One client at a time, detaching the old one
+    /// (with `Inspector.detached`) when a new one attaches.
+    ///
+    /// A request the old one is still waiting on goes unanswered, though a
+    /// forwarder may already have passed it on.
+    ///
+    ReplaceOld,
+}
+
+///
+/// Sessions currently attached, per target,
+/// and each target's [`AttachPolicy`].
+///
+/// Cloneable, and shared between every session
+/// started with the same [`SessionOptions`].
+///
+#[derive(Debug, Clone, Default)]
+pub struct Attached {
+    inner: Arc<Mutex<Sessions>>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    ///
+    /// Target -> attached sessions (by id), and how to tell them they've been replaced.
+    ///
+    targets: HashMap<String, Vec<(u64, oneshot::Sender<()>)>>,
+
+    ///
+    /// Target -> policy, for targets that don't allow [`AttachPolicy::Many`].
+    ///
+    policies: HashMap<String, AttachPolicy>,
+
+    ///
+    /// Session id -> what it answers, for `/json/protocol`.
+    ///
+    routes: HashMap<u64, Arc<RwLock<Routes>>>,
+
+    ///
+    /// What the last session to end answered, for when none are attached.
+    ///
+    last_methods: Vec<String>,
+}
+
+impl Attached {
+    ///
+    /// How many sessions are attached to `target`.
+    ///
+    pub fn count(&self, target: &str) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .targets
+            .get(target)
+            .map(Vec::len)
+            .unwrap_or_default()
+    }
+
+    pub fn policy(&self, target: &str) -> AttachPolicy {
+        self.inner
+            .lock()
+            .unwrap()
+            .policies
+            .get(target)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    ///
+    /// Change how `target` handles new clients
+    /// (already attached ones are left alone until the next one comes along).
+    ///
+    pub fn set_policy(&self, target: impl Into<String>, policy: AttachPolicy) {
+        let mut sessions = self.inner.lock().unwrap();
+        match policy {
+            AttachPolicy::Many => sessions.policies.remove(&target.into()),
+            policy => sessions.policies.insert(target.into(), policy),
+        };
+    }
+
+    ///
+    /// Attach `session` to its target, if the target's policy
+    /// (and `limit`, for [`AttachPolicy::Many`]) allows it.
+    ///
+    /// The session stays attached until the guard is dropped.
+    ///
+    pub(crate) fn attach(
+        &self,
+        session: &SessionInfo,
+        limit: Option<usize>,
+    ) -> Option<AttachGuard> {
+        let mut sessions = self.inner.lock().unwrap();
+        let policy = sessions
+            .policies
+            .get(&session.target)
+            .copied()
+            .unwrap_or_default();
+        let attached = sessions.targets.entry(session.target.clone()).or_default();
+
+        match policy {
+            AttachPolicy::Many if limit.is_some_and(|limit| attached.len() >= limit) => {
+                return None
+            }
+            AttachPolicy::RejectNew if !attached.is_empty() => return None,
+            AttachPolicy::ReplaceOld => {
+                for (_, replaced) in attached.drain(..) {
+                    let _ = replaced.send(());
+                }
+            }
+            _ => {}
+        }
+
+        let (tx, replaced) = oneshot::channel();
+        attached.push((session.id, tx));
+
+        Some(AttachGuard {
+            attached: self.clone(),
+            target: session.target.clone(),
+            id: session.id,
+            replaced,
+        })
+    }
+
+    ///
+    /// Every method the attached sessions answer (see [`Handler::supported_methods`]),
+    /// or what the last one did, if none are.
+    ///
+    /// Empty until the first session starts.
+    ///
+    /// [`Handler::supported_methods`]: crate::Handler::supported_methods
+    ///
+    pub fn supported_methods(&self) -> Vec<String> {
+        let sessions = self.inner.lock().unwrap();
+        if sessions.routes.is_empty() {
+            return sessions.last_methods.clone();
+        }
+
+        let methods: BTreeSet<String> = sessions
+            .routes
+            .values()
+            .flat_map(|routes| routes.read().unwrap().supported_methods())
+            .collect();
+        methods.into_iter().collect()
+    }
+
+    ///
+    /// Mark which of `targets` have sessions attached.
+    ///
+    pub(crate) fn mark(&self, targets: &mut [Target]) {
+        let sessions = self.inner.lock().unwrap();
+        for target in targets {
+            target.attached = sessions.targets.contains_key(&target.id);
+        }
+    }
+}
+
+pub(crate) struct AttachGuard {
+    attached: Attached,
+    target: String,
+    id: u64,
+    replaced: oneshot::Receiver<()>,
+}
+
+impl AttachGuard {
+    ///
+    /// Let [`Attached::supported_methods`] know what this session answers.
+    ///
+    pub(crate) fn answers(&self, routes: &Arc<RwLock<Routes>>) {
+        let mut sessions = self.attached.inner.lock().unwrap();
+        sessions.routes.insert(self.id, routes.clone());
+    }
+
+    ///
+    /// Resolves once another session has replaced this one
+    /// (see [`AttachPolicy::ReplaceOld`]).
+    ///
+    pub(crate) async fn replaced(&mut self) {
+        if (&mut self.replaced).await.is_err() {
+            futures::future::pending().await
+        }
+    }
+}
+
+impl Drop for AttachGuard {
+    fn drop(&mut self) {
+        let mut sessions = self.attached.inner.lock().unwrap();
+        if let Some(attached) = sessions.targets.get_mut(&self.target) {
+            attached.retain(|(id, _)| *id != self.id);
+            if attached.is_empty() {
+                sessions.targets.remove(&self.target);
+            }
+        }
+
+        if let Some(routes) = sessions.routes.remove(&self.id) {
+            sessions.last_methods = routes.read().unwrap().supported_methods();
+        }
+    }
+}
+
+///
+/// Everything that shapes a single session's traffic.
+///
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    pub channels: Channels,
+
+    ///
+    /// `None` turns off pings and the idle timeout.
+    ///
+    pub keepalive: Option<Keepalive>,
+
+    pub limits: Limits,
+
+    ///
+    /// Sessions attached so far (shared between clones of these options).
+    ///
+    pub attached: Attached,
+
+    ///
+    /// Where to record every message to, if anywhere.
+    ///
+    pub recorder: Option<Recorder>,
+
+    ///
+    /// What to leave out of traffic logs.
+    ///
+    pub redactor: Option<Redactor>,
+
+    ///
+    /// Where to count sessions, requests and events, if anywhere.
+    ///
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Metrics>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            channels: Channels::default(),
+            keepalive: Some(Keepalive::default()),
+            limits: Limits::default(),
+            attached: Attached::default(),
+            recorder: None,
+            redactor: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+}
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+///
+/// Who a session is.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    ///
+    /// Unique (within this process).
+    ///
+    pub id: u64,
+
+    ///
+    /// The target's id.
+    ///
+    pub target: String,
+}
+
+impl SessionInfo {
+    ///
+    /// A new session, with a fresh id.
+    ///
+    pub fn new(target: impl Into<String>) -> Self {
+        Self {
+            id: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+            target: target.into(),
+        }
+    }
+}
+
+///
+/// Ticks every `keepalive.interval`, or never.
+///
+async fn tick(ping: &mut Option<tokio::time::Interval>) {
+    match ping {
+        Some(ping) => {
+            ping.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+///
+/// Everything queued up for a session, in the order it was sent,
+/// with events (and only events) subject to a [`Backpressure`] policy.
+///
+struct Outbox {
+    queue: VecDeque<Response>,
+
+    ///
+    /// How many of `queue` are events.
+    ///
+    events: usize,
+    capacity: usize,
+
+    ///
+    /// How many replies can wait, before we stop taking anything in.
+    ///
+    replies: usize,
+    backpressure: Backpressure,
+    dropped: u64,
+
+    ///
+    /// Nothing more is coming (the session's over, or the client's gone).
+    ///
+    closed: bool,
+}
+
+impl Outbox {
+    fn new(channels: &Channels) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            events: 0,
+            capacity: channels.events.max(1),
+            replies: channels.replies.max(1),
+            backpressure: channels.backpressure,
+            dropped: 0,
+            closed: false,
+        }
+    }
 
-#[cfg(feature = "logging")]
-pub const LOGGING: bool = true;
+    fn is_event(res: &Response) -> bool {
+        res.id.is_none() && res.method.is_some()
+    }
 
-#[cfg(not(feature = "logging"))]
-pub const LOGGING: bool = false;
+    ///
+    /// Whether to take anything more in, leaving senders waiting if not.
+    ///
+    /// Replies can't be dropped, so a client that never reads them
+    /// stops us (and so the session) once enough are waiting.
+    /// Events only do under [`Backpressure::Block`].
+    ///
+    fn has_room(&self) -> bool {
+        self.queue.len() - self.events < self.replies
+            && (self.backpressure != Backpressure::Block || self.events < self.capacity)
+    }
 
-pub fn departing(mut out: SplitSink<WebSocket, Message>) -> [Sender<jsonrpc::Response>; 3] {
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<jsonrpc::Response>(16);
+    fn push(&mut self, res: Response) {
+        if !Self::is_event(&res) {
+            self.queue.push_back(res);
+            return;
+        }
 
-    tokio::spawn(async move {
-        while let Some(res) = rx.recv().await {
-            out.send(Message::text(
-                &serde_json::to_string(&res).expect("Could not Serialize Response"),
-            ))
-            .await
-            .expect("Could not send off Response");
+        // Drop the older one, rather than replacing it in place,
+        // so the newer one doesn't overtake anything sent before it.
+        if self.backpressure == Backpressure::Coalesce {
+            if let Some(i) = self
+                .queue
+                .iter()
+                .position(|q| Self::is_event(q) && q.method == res.method)
+            {
+                self.queue.remove(i);
+                self.events -= 1;
+                self.dropped += 1;
+            }
+        }
 
-            // Debug print.
-            if LOGGING {
-                println!("--> {res}");
+        if self.backpressure != Backpressure::Block && self.events >= self.capacity {
+            if let Some(i) = self.queue.iter().position(Self::is_event) {
+                self.queue.remove(i);
+                self.events -= 1;
+                self.dropped += 1;
             }
         }
+
+        self.queue.push_back(res);
+        self.events += 1;
+    }
+
+    fn pop(&mut self) -> Option<Response> {
+        let res = self.queue.pop_front()?;
+        if Self::is_event(&res) {
+            self.events -= 1;
+        }
+        Some(res)
+    }
+}
+
+///
+/// An [`Outbox`], filled by one task and emptied by another.
+///
+struct Shared {
+    outbox: Mutex<Outbox>,
+
+    ///
+    /// Something was queued (or the outbox closed).
+    ///
+    queued: Notify,
+
+    ///
+    /// Something was sent (or the client went away).
+    ///
+    popped: Notify,
+}
+
+impl Shared {
+    fn outbox(&self) -> std::sync::MutexGuard<'_, Outbox> {
+        self.outbox.lock().unwrap()
+    }
+
+    fn close(&self) {
+        self.outbox().closed = true;
+        self.queued.notify_one();
+        self.popped.notify_one();
+    }
+}
+
+///
+/// Send replies and events (all through the returned channel)
+/// to the client, in order, until `done` resolves and
+/// everything sent before then has gone out.
+///
+/// Events are taken off the channel as soon as they're sent,
+/// so [`Backpressure`] applies even while the client is stalled.
+///
+pub fn departing(
+    mut out: impl Sink<Frame, Error = anyhow::Error> + Send + Unpin + 'static,
+    options: &SessionOptions,
+    info: &SessionInfo,
+    done: impl Future<Output = ()> + Send + 'static,
+) -> Sender<jsonrpc::Response> {
+    let SessionOptions {
+        channels,
+        keepalive,
+        recorder,
+        redactor,
+        #[cfg(feature = "metrics")]
+        metrics,
+        ..
+    } = options.clone();
+    let info = info.clone();
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<jsonrpc::Response>(channels.replies.max(1));
+
+    let shared = Arc::new(Shared {
+        outbox: Mutex::new(Outbox::new(&channels)),
+        queued: Notify::new(),
+        popped: Notify::new(),
     });
 
-    [tx.clone(), tx.clone(), tx]
+    // Queue everything up as soon as it's sent.
+    let queue = shared.clone();
+    tokio::spawn(
+        async move {
+            tokio::pin!(done);
+
+            loop {
+                let (room, closed) = {
+                    let outbox = queue.outbox();
+                    (outbox.has_room(), outbox.closed)
+                };
+                if closed {
+                    break;
+                }
+
+                tokio::select! {
+                    biased;
+                    _ = &mut done => {
+                        // Whatever was sent before the session ended still goes out.
+                        while let Ok(res) = rx.try_recv() {
+                            queue.outbox().push(res);
+                        }
+                        break;
+                    }
+                    _ = queue.popped.notified(), if !room => {}
+                    res = rx.recv(), if room => match res {
+                        Some(res) => {
+                            queue.outbox().push(res);
+                            queue.queued.notify_one();
+                        }
+                        None => break,
+                    },
+                }
+            }
+
+            queue.close();
+        }
+        .in_current_span(),
+    );
+
+    tokio::spawn(
+        async move {
+            let mut ping = keepalive.map(|k| {
+                let mut ping = tokio::time::interval(k.interval);
+                ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                ping
+            });
+
+            loop {
+                // Pings go out on time however busy we are, or a client that only
+                // listens (never sending a thing) would be closed as idle.
+                if tick(&mut ping).now_or_never().is_some()
+                    && out.send(Frame::Ping(vec![])).await.is_err()
+                {
+                    break;
+                }
+
+                let (res, dropped) = {
+                    let mut outbox = shared.outbox();
+                    let res = outbox.pop();
+                    (res, std::mem::take(&mut outbox.dropped))
+                };
+
+                if dropped > 0 {
+                    tracing::warn!(dropped, "Client can't keep up, dropped events");
+
+                    #[cfg(feature = "metrics")]
+                    if let Some(ref metrics) = metrics {
+                        metrics.dropped(dropped);
+                    }
+                }
+
+                let res = match res {
+                    Some(res) => {
+                        shared.popped.notify_one();
+                        res
+                    }
+                    None if shared.outbox().closed => break,
+                    None => {
+                        tokio::select! {
+                            _ = shared.queued.notified() => {}
+                            _ = tick(&mut ping) => {
+                                if out.send(Frame::Ping(vec![])).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                if out
+                    .send(Frame::Text(
+                        serde_json::to_string(&res).expect("Could not Serialize Response"),
+                    ))
+                    .await
+                    .is_err()
+                {
+                    // Client's gone, arriving() will wind up the session.
+                    break;
+                }
+
+                let direction = if Outbox::is_event(&res) {
+                    Direction::Event
+                } else {
+                    Direction::Reply
+                };
+
+                if let Some(ref recorder) = recorder {
+                    recorder.record(&info, direction, &res);
+                }
+
+                #[cfg(feature = "metrics")]
+                if let (Some(metrics), Direction::Event) = (&metrics, direction) {
+                    metrics.event();
+                }
+
+                log_departing(&res, redactor.as_ref());
+            }
+
+            shared.close();
+
+            // Session's over (or the client went away), say goodbye.
+            let _ = out.send(Frame::Close(None)).await;
+            let _ = out.close().await;
+        }
+        .in_current_span(),
+    );
+
+    tx
 }
 
 pub fn arriving(
-    mut arriving: SplitStream<WebSocket>,
+    mut arriving: impl Stream<Item = anyhow::Result<Frame>> + Send + Unpin + 'static,
     err_tx: Sender<jsonrpc::Response>,
+    options: &SessionOptions,
+    info: &SessionInfo,
 ) -> Receiver<jsonrpc::Request> {
-    let (tx, rx) = tokio::sync::mpsc::channel::<jsonrpc::Request>(16);
-
-    tokio::spawn(async move {
-        while let Some(msg) = arriving.next().await.and_then(Result::ok) {
-            let msg = match msg.to_str() {
-                Ok(st) => st,
-                Err(_) => {
-                    err_tx
-                        .send(jsonrpc::parse_error())
+    let keepalive = options.keepalive;
+    let limits = options.limits;
+    let recorder = options.recorder.clone();
+    let redactor = options.redactor.clone();
+    let info = info.clone();
+    let (tx, rx) = tokio::sync::mpsc::channel::<jsonrpc::Request>(options.channels.requests.max(1));
+
+    tokio::spawn(
+        async move {
+            let mut bucket = limits.requests_per_second.map(Bucket::new);
+
+            loop {
+                let read = async {
+                    match keepalive {
+                        Some(k) => tokio::time::timeout(k.idle_timeout, arriving.next())
+                            .await
+                            .map_err(|_| k.idle_timeout),
+                        None => Ok(arriving.next().await),
+                    }
+                };
+
+                let next = tokio::select! {
+                    next = read => match next {
+                        Ok(next) => next,
+                        Err(timeout) => {
+                            tracing::info!(?timeout, "Client idle, closing");
+                            break;
+                        }
+                    },
+                    // The session was wound up from our end (e.g. replaced by a newer one).
+                    _ = tx.closed() => break,
+                };
+
+                let msg = match next {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        tracing::info!(error = %e, "Connection error, closing");
+                        break;
+                    }
+                    None => break,
+                };
+
+                let msg = match msg {
+                    Frame::Text(text) => text,
+                    // Transports with pings (i.e. WebSockets) answer them on their own,
+                    // and pongs only matter for resetting the idle timeout above.
+                    Frame::Ping(_) | Frame::Pong(_) => continue,
+                    Frame::Close(_) => break,
+                    Frame::Binary(_) => {
+                        if err_tx.send(jsonrpc::parse_error()).await.is_err() {
+                            // Nothing's going out any more, so there's no session left.
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                let too_big = msg.len() > limits.max_message;
+                if too_big || too_deep(&msg, limits.max_depth) {
+                    let error = if too_big {
+                        format!("Message too big (over {} bytes)", limits.max_message)
+                    } else {
+                        format!("Message nested too deeply (over {})", limits.max_depth)
+                    };
+                    tracing::warn!(%error, "Message over limits");
+
+                    if err_tx
+                        .send(jsonrpc::limit_exceeded(None, error))
                         .await
-                        .expect("Could not send Parse Error");
+                        .is_err()
+                    {
+                        break;
+                    }
                     continue;
                 }
-            };
 
-            let req = match serde_json::from_str(msg) {
-                Ok(req) => req,
-                Err(_) => {
-                    err_tx
-                        .send(jsonrpc::invalid_request(None, None))
-                        .await
-                        .expect("Could not send Parse Error");
-                    continue;
+                let req = match serde_json::from_str::<jsonrpc::Request>(&msg) {
+                    Ok(req) => req,
+                    Err(_) => {
+                        if err_tx
+                            .send(jsonrpc::invalid_request(None, None))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                if let Some(ref mut bucket) = bucket {
+                    if !bucket.take() {
+                        tracing::warn!(method = %req.method, "Too many requests");
+
+                        if err_tx
+                            .send(jsonrpc::limit_exceeded(req.id, "Too many requests"))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
                 }
-            };
 
-            // Debug print.
-            if LOGGING {
-                println!("<-- {req}");
-            }
+                if let Some(ref recorder) = recorder {
+                    recorder.record(&info, Direction::Request, &req);
+                }
+
+                log_arriving(&req, redactor.as_ref());
 
-            tx.send(req).await.expect("Couldn't send incoming request");
+                if tx.send(req).await.is_err() {
+                    break;
+                }
+            }
         }
-    });
+        .in_current_span(),
+    );
 
     rx
 }
 
+///
+/// Allows `rate` requests per second, in bursts of up to `rate`.
+///
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+///
+/// Whether objects/arrays in `json` nest deeper than `max`
+/// (without parsing it, which is what we're guarding).
+///
+fn too_deep(json: &str, max: usize) -> bool {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+
+    for b in json.bytes() {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match b {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    false
+}
+
+fn log_arriving(req: &jsonrpc::Request, redactor: Option<&Redactor>) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+
+    let mut params = req.params.clone();
+
+    if let Some(redactor) = redactor {
+        if !redactor.allows(&req.method) {
+            return;
+        }
+        redactor.redact(&req.method, &mut params);
+    }
+
+    tracing::debug!(id = ?req.id, method = %req.method, params = %params, "<--");
+}
+
+fn log_departing(res: &Response, redactor: Option<&Redactor>) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+
+    let method = res.method.as_deref().unwrap_or_default();
+    let mut result = res.result.clone();
+    let mut params = res.params.clone();
+    let mut error = res.error.clone();
+
+    if let Some(redactor) = redactor {
+        if !redactor.allows(method) {
+            return;
+        }
+        result.iter_mut().for_each(|r| redactor.redact(method, r));
+        params.iter_mut().for_each(|p| redactor.redact(method, p));
+        error
+            .iter_mut()
+            .filter_map(|e| e.data.as_mut())
+            .for_each(|d| redactor.redact(method, d));
+    }
+
+    tracing::debug!(
+        id = ?res.id,
+        method,
+        result = ?result,
+        params = ?params,
+        error = ?error,
+        "-->"
+    );
+}
+
 pub fn dispatch_event(tx: &Sender<Response>, event: impl Into<Response> + Send + 'static) {
+    // Straight onto the channel if there's room,
+    // so it goes out before anything sent after it (e.g. the reply).
+    let event = match tx.try_send(event.into()) {
+        Ok(()) | Err(TrySendError::Closed(_)) => return,
+        Err(TrySendError::Full(event)) => event,
+    };
+
     let tx = tx.clone();
-    tokio::spawn(async move {
-        tx.send(event.into()).await.expect("Could not send event");
-    });
+    tokio::spawn(
+        async move {
+            // Nobody to send it to, if the session's over.
+            let _ = tx.send(event).await;
+        }
+        .in_current_span(),
+    );
 }
@@ -0,0 +1,323 @@
+//!
+//! What a session's frames actually travel over.
+//!
+//! Anything that can be split into a stream of inbound frames
+//! and a sink of outbound ones can be served by a [`Handler`](crate::Handler),
+//! see [`DevToolsServer::handle_client`](crate::DevToolsServer::handle_client).
+//!
+
+use std::pin::Pin;
+
+use futures::future::{ready, Ready};
+use futures_util::{
+    sink::With,
+    stream::{BoxStream, Map, SplitSink, SplitStream},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use warp::ws::{Message, WebSocket};
+
+use crate::traffic::Limits;
+
+///
+/// A single frame to or from a client.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+
+    ///
+    /// Close, optionally with a code and reason.
+    ///
+    Close(Option<(u16, String)>),
+}
+
+pub trait Transport: Send + 'static {
+    type Inbound: Stream<Item = anyhow::Result<Frame>> + Send + Unpin + 'static;
+    type Outbound: Sink<Frame, Error = anyhow::Error> + Send + Unpin + 'static;
+
+    fn split(self) -> (Self::Outbound, Self::Inbound);
+
+    ///
+    /// The session's [`Limits::max_message`](crate::Limits::max_message), given before
+    /// it's split, so transports can stop reading messages that are far too big.
+    ///
+    fn max_message(&mut self, _max: usize) {}
+
+    ///
+    /// Whether clients can answer pings, so the session's [`Keepalive`](crate::Keepalive)
+    /// can tell a quiet client from a dead one. Without pings, it's turned off.
+    ///
+    fn supports_ping(&self) -> bool {
+        true
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(msg: Message) -> Self {
+        if let Some((code, reason)) = msg.close_frame() {
+            return Frame::Close(Some((code, reason.to_string())));
+        }
+
+        if msg.is_close() {
+            Frame::Close(None)
+        } else if msg.is_ping() {
+            Frame::Ping(msg.into_bytes())
+        } else if msg.is_pong() {
+            Frame::Pong(msg.into_bytes())
+        } else if msg.is_text() {
+            Frame::Text(msg.to_str().unwrap_or_default().to_string())
+        } else {
+            Frame::Binary(msg.into_bytes())
+        }
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Text(text) => Message::text(text),
+            Frame::Binary(bytes) => Message::binary(bytes),
+            Frame::Ping(bytes) => Message::ping(bytes),
+            Frame::Pong(bytes) => Message::pong(bytes),
+            Frame::Close(Some((code, reason))) => Message::close_with(code, reason),
+            Frame::Close(None) => Message::close(),
+        }
+    }
+}
+
+type ToMessage = fn(Frame) -> Ready<anyhow::Result<Message>>;
+type ToFrame = fn(Result<Message, warp::Error>) -> anyhow::Result<Frame>;
+
+impl Transport for WebSocket {
+    type Inbound = Map<SplitStream<WebSocket>, ToFrame>;
+    type Outbound = With<
+        SplitSink<WebSocket, Message>,
+        Message,
+        Frame,
+        Ready<anyhow::Result<Message>>,
+        ToMessage,
+    >;
+
+    fn split(self) -> (Self::Outbound, Self::Inbound) {
+        let (tx, rx) = StreamExt::split(self);
+
+        (
+            tx.with((|frame| ready(Ok(frame.into()))) as ToMessage),
+            rx.map((|msg| Ok(msg?.into())) as ToFrame),
+        )
+    }
+}
+
+impl<S> Transport for tokio_tungstenite::WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Inbound = BoxStream<'static, anyhow::Result<Frame>>;
+    type Outbound = BoxSink;
+
+    fn split(self) -> (Self::Outbound, Self::Inbound) {
+        use tokio_tungstenite::tungstenite::{
+            protocol::{frame::coding::CloseCode, CloseFrame},
+            Message,
+        };
+
+        let (tx, rx) = StreamExt::split(self);
+
+        let outbound = tx.with(|frame: Frame| {
+            ready(Ok::<_, anyhow::Error>(match frame {
+                Frame::Text(text) => Message::Text(text),
+                Frame::Binary(bytes) => Message::Binary(bytes),
+                Frame::Ping(bytes) => Message::Ping(bytes),
+                Frame::Pong(bytes) => Message::Pong(bytes),
+                Frame::Close(close) => Message::Close(close.map(|(code, reason)| CloseFrame {
+                    code: CloseCode::from(code),
+                    reason: reason.into(),
+                })),
+            }))
+        });
+
+        let inbound = rx.map(|msg| {
+            Ok(match msg? {
+                Message::Text(text) => Frame::Text(text),
+                Message::Binary(bytes) => Frame::Binary(bytes),
+                Message::Ping(bytes) => Frame::Ping(bytes),
+                Message::Pong(bytes) => Frame::Pong(bytes),
+                Message::Close(close) => {
+                    Frame::Close(close.map(|c| (c.code.into(), c.reason.into_owned())))
+                }
+                Message::Frame(frame) => Frame::Binary(frame.into_data()),
+            })
+        });
+
+        (Box::pin(outbound), inbound.boxed())
+    }
+}
+
+pub type BoxSink = Pin<Box<dyn Sink<Frame, Error = anyhow::Error> + Send>>;
+
+///
+/// Chrome's `--remote-debugging-pipe` protocol:
+/// JSON messages delimited by `\0`, over any reader/writer pair.
+///
+/// There's no ping/pong or close frames here, so pings are dropped
+/// (and sessions have no keepalive), and a close just shuts down the writer.
+///
+/// Only the first [`Limits::max_message`] bytes (16 MiB, unless the session says
+/// otherwise) of a message are read; anything longer is skipped, and rejected.
+///
+pub struct Pipe<R, W> {
+    reader: R,
+    writer: W,
+
+    ///
+    /// Bytes to read of a message before giving up on it.
+    ///
+    max_message: usize,
+}
+
+impl<R, W> Pipe<R, W>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            max_message: Limits::default().max_message,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Pipe<tokio::fs::File, tokio::fs::File> {
+    ///
+    /// The pipe Chrome would be given: we read from fd 3, and write to fd 4.
+    ///
+    /// # Safety
+    /// File descriptors 3 and 4 must be open (i.e. set up by the parent process),
+    /// and not owned by anything else in this process.
+    ///
+    pub unsafe fn from_fds() -> Self {
+        use std::os::fd::FromRawFd;
+
+        Self::new(
+            tokio::fs::File::from_std(std::fs::File::from_raw_fd(3)),
+            tokio::fs::File::from_std(std::fs::File::from_raw_fd(4)),
+        )
+    }
+}
+
+impl<R, W> Transport for Pipe<R, W>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    type Inbound = BoxStream<'static, anyhow::Result<Frame>>;
+    type Outbound = BoxSink;
+
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn split(self) -> (Self::Outbound, Self::Inbound) {
+        let (reader, max) = (BufReader::new(self.reader), self.max_message);
+        let inbound = futures::stream::unfold(reader, move |mut reader| async move {
+            match read_message(&mut reader, max).await {
+                Ok(None) => None,
+                Ok(Some(frame)) => Some((Ok(frame), reader)),
+                Err(e) => Some((Err(e.into()), reader)),
+            }
+        });
+
+        let outbound = futures::sink::unfold(self.writer, |mut writer, frame: Frame| async {
+            match frame {
+                Frame::Text(text) => write_message(&mut writer, text.as_bytes()).await?,
+                Frame::Binary(bytes) => write_message(&mut writer, &bytes).await?,
+                Frame::Ping(_) | Frame::Pong(_) => {}
+                Frame::Close(_) => writer.shutdown().await?,
+            }
+
+            Ok::<_, anyhow::Error>(writer)
+        });
+
+        (Box::pin(outbound), inbound.boxed())
+    }
+
+    fn max_message(&mut self, max: usize) {
+        self.max_message = max;
+    }
+}
+
+///
+/// The next message, or `None` at the end.
+///
+/// Anything over `max` bytes is cut short (and the rest skipped),
+/// so the session can reject it without us holding on to all of it.
+///
+async fn read_message(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    max: usize,
+) -> std::io::Result<Option<Frame>> {
+    let mut buf = vec![];
+
+    // One byte over the limit (with no `\0`) is enough to know it's too big.
+    let cap = max.saturating_add(1);
+    let read = reader.take(cap as u64).read_until(b'\0', &mut buf).await?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+
+    if buf.last() == Some(&b'\0') {
+        buf.pop();
+    } else if read == cap {
+        skip_message(reader).await?;
+        let text = String::from_utf8_lossy(&buf).into_owned();
+        return Ok(Some(Frame::Text(text)));
+    }
+
+    Ok(Some(
+        String::from_utf8(buf)
+            .map(Frame::Text)
+            .unwrap_or_else(|e| Frame::Binary(e.into_bytes())),
+    ))
+}
+
+///
+/// Read up to (and including) the next `\0`, without keeping any of it.
+///
+async fn skip_message(reader: &mut (impl AsyncBufRead + Unpin)) -> std::io::Result<()> {
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(());
+        }
+
+        match available.iter().position(|b| *b == b'\0') {
+            Some(at) => {
+                reader.consume(at + 1);
+                return Ok(());
+            }
+            None => {
+                let n = available.len();
+                reader.consume(n);
+            }
+        }
+    }
+}
+
+async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &[u8],
+) -> std::io::Result<()> {
+    writer.write_all(message).await?;
+    writer.write_all(b"\0").await?;
+    writer.flush().await
+}
@@ -1,10 +1,23 @@
-use std::{cell::RefCell, collections::HashMap, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock as SyncRwLock,
+    },
+};
 
-use crate::jsonrpc::{self, Request, Response};
+use crate::{
+    jsonrpc::{self, Request, Response},
+    meta::BrowserVersion,
+    registry::Registry,
+    roles::Role,
+};
 use chrome_devtools_api::Command;
+use serde_json::json;
 use tokio::sync::{
     mpsc::{channel, Receiver, Sender},
-    RwLock,
+    oneshot, Mutex as AsyncMutex,
 };
 
 pub trait Listener<C: Command>:
@@ -67,6 +80,10 @@ where
 ///
 /// DevTools-side of communications.
 ///
+/// Requests reach the other side with ids of our own (the client's are put
+/// back on the replies), so one forwarder can be shared between sessions
+/// (see [`Registry::forward`]) without them getting each other's replies.
+///
 pub struct ForwarderIn {
     ///
     /// Methods/domains to listen for.
@@ -81,7 +98,13 @@ pub struct ForwarderIn {
     ///
     /// Responses out (only one per request!)
     ///
-    outbound: RwLock<Receiver<Response>>,
+    outbound: AsyncMutex<Receiver<Response>>,
+
+    ///
+    /// Our id -> whoever's waiting on its reply.
+    ///
+    pending: Mutex<HashMap<u64, oneshot::Sender<Response>>>,
+    next_id: AtomicU64,
 }
 
 impl ForwarderIn {
@@ -93,7 +116,9 @@ impl ForwarderIn {
         Self {
             actions,
             inbound,
-            outbound: RwLock::new(outbound),
+            outbound: AsyncMutex::new(outbound),
+            pending: Default::default(),
+            next_id: AtomicU64::new(1),
         }
     }
 
@@ -106,10 +131,107 @@ impl ForwarderIn {
             .any(|d| action.to_lowercase().starts_with(&d.to_lowercase()))
     }
 
-    pub async fn send(&self, req: Request, res: &Sender<Response>) -> Response {
-        self.inbound.send((req, res.clone())).await.unwrap();
-        // TODO: Change me back.
-        self.outbound.write().await.recv().await.unwrap_or_default()
+    ///
+    /// Methods/domains this forwarder listens for.
+    ///
+    pub fn actions(&self) -> &[String] {
+        &self.actions
+    }
+
+    ///
+    /// Requests sent, but not yet picked up.
+    ///
+    #[cfg(feature = "metrics")]
+    pub(crate) fn queued(&self) -> usize {
+        self.inbound.max_capacity() - self.inbound.capacity()
+    }
+
+    pub async fn send(&self, mut req: Request, res: &Sender<Response>) -> Response {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let original = req.id.replace(id.into());
+
+        let (tx, mut rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        let _waiting = Waiting {
+            pending: &self.pending,
+            id,
+        };
+
+        if self.inbound.send((req, res.clone())).await.is_err() {
+            return gone(original);
+        }
+
+        let reply = loop {
+            // Whoever holds the receiver hands out everyone's replies.
+            let mut outbound = tokio::select! {
+                reply = &mut rx => break reply.ok(),
+                outbound = self.outbound.lock() => outbound,
+            };
+
+            tokio::select! {
+                biased;
+                reply = &mut rx => break reply.ok(),
+                reply = outbound.recv() => match reply {
+                    Some(reply) => self.deliver(reply),
+                    None => break None,
+                },
+            }
+        };
+
+        match reply {
+            Some(mut reply) => {
+                reply.id = original;
+                reply
+            }
+            None => gone(original),
+        }
+    }
+
+    ///
+    /// Hand a reply to the request it's for.
+    ///
+    fn deliver(&self, reply: Response) {
+        let mut pending = self.pending.lock().unwrap();
+        let waiter = match reply.id.as_ref().and_then(|id| id.as_u64()) {
+            Some(id) if pending.contains_key(&id) => pending.remove(&id),
+            // Replies without (our) ids can only be told apart one request at a time.
+            _ if pending.len() == 1 => pending.drain().next().map(|(_, waiter)| waiter),
+            _ => None,
+        };
+
+        match waiter {
+            Some(waiter) => {
+                let _ = waiter.send(reply);
+            }
+            None => tracing::warn!(id = ?reply.id, "Dropped forwarder reply for no known request"),
+        }
+    }
+}
+
+///
+/// Reply to `id`, for when the other side of a forwarder has gone away.
+///
+fn gone(id: Option<serde_json::Value>) -> Response {
+    Response {
+        id,
+        result: None,
+        error: Some(jsonrpc::RpcError::server_error("Forwarder closed")),
+        ..Default::default()
+    }
+}
+
+///
+/// A request [`ForwarderIn::send`] is waiting on, forgotten once it stops
+/// (answered or not, e.g. when the session is replaced mid-request).
+///
+struct Waiting<'a> {
+    pending: &'a Mutex<HashMap<u64, oneshot::Sender<Response>>>,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
     }
 }
 
@@ -147,23 +269,34 @@ impl ForwarderOut {
 ///
 /// Utility struct to generate the required channels.
 ///
-pub struct Forwarder(Vec<String>);
+pub struct Forwarder {
+    actions: Vec<String>,
+    capacity: usize,
+}
 
 impl Forwarder {
     pub fn new<T: ToString>(actions: impl IntoIterator<Item = T>) -> Self {
-        Self(actions.into_iter().map(|el| el.to_string()).collect())
+        Self {
+            actions: actions.into_iter().map(|el| el.to_string()).collect(),
+            capacity: 8,
+        }
+    }
+
+    ///
+    /// Capacity of each direction's channel (default 8, at least 1).
+    ///
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
     }
 
     pub fn split(self) -> (ForwarderIn, ForwarderOut) {
-        let (inbound_in, inbound_out) = channel(8);
-        let (outbound_in, outbound_out) = channel(8);
+        let capacity = self.capacity.max(1);
+        let (inbound_in, inbound_out) = channel(capacity);
+        let (outbound_in, outbound_out) = channel(capacity);
 
         (
-            ForwarderIn {
-                actions: self.0,
-                inbound: inbound_in,
-                outbound: RwLock::new(outbound_out),
-            },
+            ForwarderIn::new(self.actions, inbound_in, outbound_out),
             ForwarderOut {
                 inbound: inbound_out,
                 outbound: outbound_in,
@@ -172,20 +305,189 @@ impl Forwarder {
     }
 }
 
-#[derive(Default)]
+///
+/// Method answered automatically from what a [`Handler`] has registered,
+/// unless a forwarder or listener claims it first.
+///
+pub const SCHEMA_GET_DOMAINS: &str = "Schema.getDomains";
+
+///
+/// The domain part of a method or forwarder action,
+/// e.g. `DOM` for `DOM.getDocument` and `Runtime` for `Runtime.`.
+///
+pub fn domain_of(action: &str) -> &str {
+    action.split('.').next().unwrap_or(action)
+}
+
+///
+/// Unique domains (case-insensitively) out of a list of
+/// methods and forwarder actions.
+///
+pub fn domains<'a>(actions: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    actions
+        .into_iter()
+        .map(domain_of)
+        .filter(|d| !d.is_empty())
+        .fold(BTreeMap::new(), |mut acc, d| {
+            acc.entry(d.to_lowercase()).or_insert_with(|| d.to_string());
+            acc
+        })
+        .into_values()
+        .collect()
+}
+
+fn supported_methods<'a>(
+    methods: impl IntoIterator<Item = &'a String>,
+    forwarders: impl IntoIterator<Item = &'a ForwarderIn>,
+) -> Vec<String> {
+    methods
+        .into_iter()
+        .cloned()
+        .chain(
+            forwarders
+                .into_iter()
+                .flat_map(|f| f.actions().iter().cloned()),
+        )
+        .chain(std::iter::once(SCHEMA_GET_DOMAINS.to_string()))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+///
+/// Methods a [`Fallback`] has handled in any session so far,
+/// so each is only logged the first time.
+///
+static FALLBACK_SEEN: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+///
+/// What to do with requests that no forwarder or listener claimed.
+///
+#[derive(Clone, Default)]
+pub enum Fallback {
+    ///
+    /// Reply with an `Invalid Request` error.
+    ///
+    #[default]
+    Reject,
+
+    ///
+    /// Reply `{}` to whole domains (`Page` or `Page.`), or
+    /// listed methods (`Page.setAdBlockingEnabled`).
+    /// `*` acknowledges everything.
+    ///
+    Acknowledge(Vec<String>),
+
+    ///
+    /// Catch-all raw listener.
+    ///
+    Listener(Arc<RawListener>),
+}
+
+impl Fallback {
+    pub fn acknowledge<T: ToString>(actions: impl IntoIterator<Item = T>) -> Self {
+        Self::Acknowledge(actions.into_iter().map(|a| a.to_string()).collect())
+    }
+
+    pub fn listener(
+        listener: impl Fn(Request, Sender<Response>) -> Response + Sync + Send + 'static,
+    ) -> Self {
+        Self::Listener(Arc::new(listener))
+    }
+
+    ///
+    /// Try to handle `req`, giving it back if we can't.
+    ///
+    fn handle(&self, req: Request, tx: &Sender<Response>) -> Result<Response, Request> {
+        match self {
+            Fallback::Reject => Err(req),
+            Fallback::Acknowledge(actions) => {
+                if actions.iter().any(|a| action_matches(a, &req.method)) {
+                    Ok(Response::reply(&req, None))
+                } else {
+                    Err(req)
+                }
+            }
+            Fallback::Listener(l) => Ok(l(req, tx.clone())),
+        }
+    }
+}
+
+///
+/// Whether `method` falls under `action`: a whole domain (`Page` or `Page.`),
+/// a single method (`Page.enable`), or anything at all (`*`).
+///
+pub(crate) fn action_matches(action: &str, method: &str) -> bool {
+    if action == "*" {
+        return true;
+    }
+
+    if action.contains('.') && !action.ends_with('.') {
+        return action.eq_ignore_ascii_case(method);
+    }
+
+    domain_of(action).eq_ignore_ascii_case(domain_of(method))
+}
+
 pub struct HandlerBuilder {
     forwarders: Vec<ForwarderIn>,
     handlers: HashMap<String, Arc<RawListener>>,
+
+    ///
+    /// Command ids of registered listeners, in their original case.
+    ///
+    methods: BTreeSet<String>,
+
+    fallback: Fallback,
+
+    registry: Option<Registry>,
+
+    ///
+    /// What the session may call, if it's restricted at all.
+    ///
+    role: Option<Role>,
+
+    ///
+    /// Reported by `Schema.getDomains` (see [`BrowserVersion::protocol_version`]).
+    ///
+    protocol_version: String,
+
+    ///
+    /// Called with the events channel, once the session starts.
+    ///
+    on_start: Vec<Box<dyn FnOnce(Sender<Response>) + Send>>,
+}
+
+impl Default for HandlerBuilder {
+    fn default() -> Self {
+        Self::new(vec![], HashMap::new())
+    }
 }
 
 impl HandlerBuilder {
     pub fn new(forwarders: Vec<ForwarderIn>, handlers: HashMap<String, Arc<RawListener>>) -> Self {
         Self {
             forwarders,
+            methods: handlers.keys().cloned().collect(),
             handlers,
+            fallback: Fallback::default(),
+            registry: None,
+            role: None,
+            protocol_version: BrowserVersion::default().protocol_version,
+            on_start: vec![],
         }
     }
 
+    ///
+    /// Add an untyped listener for `method`.
+    ///
+    pub fn add_raw_listener(&mut self, method: &str, listener: Arc<RawListener>) -> &mut Self {
+        self.handlers.insert(method.to_lowercase(), listener);
+        self.methods.insert(method.to_string());
+
+        self
+    }
+
     pub fn add_listener<C: Command, L: IntoRawListener<C>>(
         &mut self,
         _: C,
@@ -193,46 +495,265 @@ impl HandlerBuilder {
     ) -> &mut Self {
         self.handlers
             .insert(C::id().to_lowercase(), listener.into_listener());
+        self.methods.insert(C::id().to_string());
 
         self
     }
 
+    ///
+    /// Every method this builder's handlers will answer, plus
+    /// the action prefixes of its forwarders.
+    ///
+    pub fn supported_methods(&self) -> Vec<String> {
+        let mut routes = Routes {
+            forwarders: vec![],
+            handlers: Default::default(),
+            methods: self.methods.clone(),
+        };
+
+        if let Some(ref registry) = self.registry {
+            registry.apply(&mut routes);
+        }
+
+        supported_methods(
+            &routes.methods,
+            self.forwarders
+                .iter()
+                .chain(routes.forwarders.iter().map(Arc::as_ref)),
+        )
+    }
+
     pub fn forward(&mut self, forwarder_in: ForwarderIn) -> &mut Self
 where {
         self.forwarders.push(forwarder_in);
         self
     }
 
+    ///
+    /// Set what happens to requests nothing else claimed.
+    ///
+    pub fn fallback(&mut self, fallback: Fallback) -> &mut Self {
+        self.fallback = fallback;
+        self
+    }
+
+    ///
+    /// Attach this session to a [`Registry`], so listeners and
+    /// forwarders can be added or removed after it's built.
+    ///
+    pub fn registry(&mut self, registry: &Registry) -> &mut Self {
+        self.registry = Some(registry.clone());
+        self
+    }
+
+    ///
+    /// Only let the session call what `role` allows.
+    ///
+    pub fn role(&mut self, role: Role) -> &mut Self {
+        self.role = Some(role);
+        self
+    }
+
+    ///
+    /// Report `version` (e.g. `"1.3"`) for every domain in `Schema.getDomains`.
+    ///
+    pub fn protocol_version(&mut self, version: impl Into<String>) -> &mut Self {
+        self.protocol_version = version.into();
+        self
+    }
+
+    ///
+    /// Run `hook` with the session's events channel as soon as it starts,
+    /// e.g. to send events unprompted.
+    ///
+    pub fn on_start(&mut self, hook: impl FnOnce(Sender<Response>) + Send + 'static) -> &mut Self {
+        self.on_start.push(Box::new(hook));
+        self
+    }
+
     pub fn build(self, tx: Sender<Response>) -> Handler {
-        Handler {
-            forwarders: self.forwarders,
+        for hook in self.on_start {
+            hook(tx.clone());
+        }
+
+        let routes = Routes {
+            forwarders: self.forwarders.into_iter().map(Arc::new).collect(),
             handlers: self.handlers,
+            methods: self.methods,
+        };
+
+        let routes = match self.registry {
+            Some(registry) => registry.attach(routes, &tx),
+            None => Arc::new(SyncRwLock::new(routes)),
+        };
+
+        Handler {
+            routes,
+            role: self.role,
+            protocol_version: self.protocol_version,
+            fallback: self.fallback,
             tx,
         }
     }
 }
 
+///
+/// Everything a [`Handler`] can route requests to.
+///
+/// Shared with a [`Registry`] (if any), which can
+/// change it while the session is live.
+///
+#[derive(Default)]
+pub(crate) struct Routes {
+    pub(crate) forwarders: Vec<Arc<ForwarderIn>>,
+    pub(crate) handlers: HashMap<String, Arc<RawListener>>,
+
+    ///
+    /// Command ids of registered listeners, in their original case.
+    ///
+    pub(crate) methods: BTreeSet<String>,
+}
+
+impl std::fmt::Debug for Routes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Routes")
+            .field("methods", &self.methods)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Routes {
+    pub(crate) fn supported_methods(&self) -> Vec<String> {
+        supported_methods(&self.methods, self.forwarders.iter().map(Arc::as_ref))
+    }
+}
+
 pub struct Handler {
-    forwarders: Vec<ForwarderIn>,
-    handlers: HashMap<String, Arc<RawListener>>,
+    routes: Arc<SyncRwLock<Routes>>,
+    role: Option<Role>,
+    protocol_version: String,
+    fallback: Fallback,
     tx: Sender<Response>,
 }
 
 impl Handler {
+    ///
+    /// Every method this handler answers, plus
+    /// the action prefixes of its forwarders.
+    ///
+    pub fn supported_methods(&self) -> Vec<String> {
+        self.routes.read().unwrap().supported_methods()
+    }
+
+    ///
+    /// Which of [`supported_methods`](Self::supported_methods) covers `method`,
+    /// if any, so metrics only ever label what we actually serve.
+    ///
+    #[cfg(feature = "metrics")]
+    pub(crate) fn supported_as(&self, method: &str) -> Option<String> {
+        let routes = self.routes.read().unwrap();
+        let forwarded = routes
+            .forwarders
+            .iter()
+            .flat_map(|f| f.actions())
+            .find(|a| method.to_lowercase().starts_with(&a.to_lowercase()));
+
+        forwarded
+            .or_else(|| {
+                routes
+                    .methods
+                    .iter()
+                    .find(|m| m.eq_ignore_ascii_case(method))
+            })
+            .cloned()
+            .or_else(|| {
+                method
+                    .eq_ignore_ascii_case(SCHEMA_GET_DOMAINS)
+                    .then(|| SCHEMA_GET_DOMAINS.to_string())
+            })
+    }
+
+    pub(crate) fn routes(&self) -> &Arc<SyncRwLock<Routes>> {
+        &self.routes
+    }
+
+    ///
+    /// Reply to `Schema.getDomains`.
+    ///
+    fn get_domains(&self, req: &Request) -> Response {
+        let methods = self.supported_methods();
+        let domains = domains(methods.iter().map(String::as_str))
+            .into_iter()
+            .map(|name| json!({ "name": name, "version": self.protocol_version }))
+            .collect::<Vec<_>>();
+
+        Response::reply(req, json!({ "domains": domains }))
+    }
+
+    #[tracing::instrument(skip_all, fields(method = %req.method, id = ?req.id))]
     pub async fn handle_incoming(&self, req: Request) -> jsonrpc::Response {
         let id = req.id.clone();
         let m = req.method.clone();
 
+        if let Some(ref role) = self.role {
+            if !role.allows(&m) {
+                tracing::warn!(method = %m, role = role.name(), "Denied call");
+                return Response {
+                    id,
+                    method: None,
+                    result: None,
+                    error: Some(jsonrpc::RpcError::server_error(format!(
+                        "'{m}' is not allowed for role '{}'",
+                        role.name()
+                    ))),
+                    ..Default::default()
+                };
+            }
+        }
+
+        // Don't hold onto the routes across an await.
+        let (forwarder, listener) = {
+            let routes = self.routes.read().unwrap();
+            (
+                routes.forwarders.iter().find(|f| f.has(&m)).cloned(),
+                routes.handlers.get(&m.to_lowercase()).cloned(),
+            )
+        };
+
         // Give forwarders precedence over normal handlers.
-        if let Some(forwarder) = self.forwarders.iter().find(|f| f.has(&m)) {
-            return forwarder.send(req, &self.tx).await;
+        if let Some(forwarder) = forwarder {
+            return with_method(forwarder.send(req, &self.tx).await, m);
         }
 
-        self.handlers
-            .get(&req.method.to_lowercase())
-            .map(|l| l(req, self.tx.clone()))
-            .unwrap_or(jsonrpc::invalid_request(id, m))
+        if let Some(l) = listener {
+            return with_method(l(req, self.tx.clone()), m);
+        }
+
+        if m.eq_ignore_ascii_case(SCHEMA_GET_DOMAINS) {
+            return self.get_domains(&req);
+        }
+
+        match self.fallback.handle(req, &self.tx) {
+            Ok(res) => {
+                if FALLBACK_SEEN.lock().unwrap().insert(m.clone()) {
+                    tracing::info!(method = %m, "Fallback handled");
+                }
+                res
+            }
+            Err(_) => jsonrpc::invalid_request(id, m),
+        }
+    }
+}
+
+///
+/// Say which method `res` is replying to, if it doesn't already
+/// (e.g. straight from a V8 backend), for traffic logs to go by.
+///
+fn with_method(mut res: Response, method: String) -> Response {
+    if res.method.as_deref().unwrap_or_default().is_empty() {
+        res.method = Some(method);
     }
+    res
 }
 
 pub trait Either<T>: Sized {